@@ -1,105 +1,146 @@
-use triple_buffer::Input;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 
-mod envelope;
+mod division;
+mod draw;
 mod filter;
+mod harmony;
+mod interpolation;
+mod lfo;
+mod limiter;
+mod midi;
+mod pan;
 mod playhead;
-
-const BUFFER_SIZE_SEC: f32 = 5.0;
-const GRAIN_NUM: usize = 128;
-const PLAY_HEADS: usize = 2;
+mod rng;
+mod saturator;
+mod trig;
+mod window;
+
+pub use division::NoteDivision;
+pub use draw::DrawData;
+pub use filter::FilterMode;
+pub use harmony::{mask_to_string, string_to_mask, Harmony, Scale};
+pub use interpolation::Quality;
+pub use lfo::{LfoShape, LfoTarget, LFO_NUM};
+pub use midi::MidiMode;
+pub use pan::{PanLaw, StereoMode};
+use playhead::Channels;
+pub use playhead::{FeedbackSrc, Source, Stealing};
+pub use saturator::SaturationMode;
+pub use trig::TriggerMode;
+pub use window::WindowShape;
+
+pub const GRAIN_NUM: usize = 128; // largest grain pool of a playhead
+pub const PLAY_HEADS: usize = 8;
 const BAR_NUM: usize = 100;
+const FREEZE_FADE_SEC: f32 = 0.01;
+const SWAP_FADE_SEC: f32 = 0.02;
+const SWAP_COPY_CHUNK: usize = 256; // samples copied into a new buffer per rendered sample
 
-#[derive(Clone)]
-pub struct DrawData {
-    pub buffer: Vec<f32>,
-    pub grains: Vec<Graindata>,
-}
-
-impl DrawData {
-    pub fn new() -> Self {
-        Self {
-            buffer: vec![0.0; BAR_NUM],
-            grains: vec![
-                Graindata {
-                    pos: 0.0,
-                    stereo_pos: 0.0,
-                    gain: 0.0,
-                };
-                GRAIN_NUM
-            ],
-        }
-    }
-}
-
-#[derive(Clone)]
-pub struct Graindata {
-    pub pos: f32,
-    pub stereo_pos: f32,
-    pub gain: f32,
+fn lerp(v0: f32, v1: f32, t: f32) -> f32 {
+    (1.0 - t) * v0 + t * v1
 }
 
 pub struct Buffer {
     pub data: Vec<(f32, f32)>,
     pub write_head: usize,
+    pub length_sec: f32,
 }
 
 impl Buffer {
-    fn get_cubic_sample(&self, pos: f32) -> (f32, f32) {
-        let len = self.data.len();
-
-        let base = pos.floor() as usize;
-
-        let t = pos - pos.floor();
-
-        let p0 = self.data[(base.wrapping_sub(1)) % len];
-        let p1 = self.data[base % len];
-        let p2 = self.data[(base + 1) % len];
-        let p3 = self.data[(base + 2) % len];
-
-        let left = p1.0
-            + 0.5
-                * t
-                * (p2.0 - p0.0
-                    + t * (2.0 * p0.0 - 5.0 * p1.0 + 4.0 * p2.0 - p3.0
-                        + t * (3.0 * (p1.0 - p2.0) + p3.0 - p0.0)));
-        let right = p1.1
-            + 0.5
-                * t
-                * (p2.1 - p0.1
-                    + t * (2.0 * p0.1 - 5.0 * p1.1 + 4.0 * p2.1 - p3.1
-                        + t * (3.0 * (p1.1 - p2.1) + p3.1 - p0.1)));
-        (left, right)
+    // ratio is the playback speed of the grain, only the sinc read uses it
+    fn get_sample(
+        &self,
+        pos: f32,
+        quality: Quality,
+        ratio: f32,
+        sinc: &interpolation::SincTable,
+    ) -> (f32, f32) {
+        match quality {
+            Quality::Eco => interpolation::linear(&self.data, pos),
+            Quality::Normal => interpolation::cubic(&self.data, pos),
+            Quality::Good => interpolation::lagrange(&self.data, pos),
+            Quality::High => interpolation::sinc(&self.data, pos, ratio, sinc),
+        }
+    }
+}
+
+// samples of the main and the sidechain buffer, both always have the same length
+pub struct BufferData {
+    pub main: Vec<(f32, f32)>,
+    pub sidechain: Vec<(f32, f32)>,
+}
+
+impl BufferData {
+    pub fn new(length: usize) -> Self {
+        Self {
+            main: vec![(0.0, 0.0); length],
+            sidechain: vec![(0.0, 0.0); length],
+        }
     }
 }
 
-#[derive(Default)]
-struct DrawBuffer {
-    sample_sum: f32,
-    sample_count: usize,
-    samples_per_bar: usize,
-    current_index: usize,
-    data: Vec<f32>,
+// a new buffer that takes over the most recent samples before it replaces the current one
+struct PendingBuffer {
+    data: BufferData,
+    length_sec: f32,
+    write_head: usize,
+    written: usize, // samples written to both buffers since it arrived
+    copied: usize,  // older samples copied over so far
 }
 
 pub struct Delay {
     pub buffer: Buffer,
-    pub draw_data: Input<DrawData>,
+    sidechain: Buffer, // records the sidechain input, it gets no feedback
+    draw_data: Arc<DrawData>,
     pub sample_rate: f32,
     play_heads: Vec<playhead::PlayHead>,
     pub feedback: f32,
-    filter: filter::StereoBiquadLowPass,
+    filter: filter::StereoFilter,
+    filter_settings: (FilterMode, f32, f32), // mode, color and resonance before modulation
+    saturator: saturator::Saturator,
+    limiter: limiter::Limiter,
     feedback_sample: (f32, f32),
+    feedback_mod: f32, // lfo offset of the feedback
     dry: f32,
     wet: f32,
-    draw_buffer: DrawBuffer,
+    draw_buffer: draw::DrawBuffer,
+    windows: window::WindowTables,
+    sinc: interpolation::SincTable,
+    quality: Quality,
+    tempo: f32,
+    playing: bool,
+    beat_pos: Option<f64>,
+    pan_law: PanLaw,
+    stereo_mode: StereoMode,
+    input_channels: usize,
+    freeze: bool,
+    freeze_amount: f32, // crossfades between writing and keeping the buffer range 0-1
+    pending_buffer: Option<PendingBuffer>,
+    old_buffer: Option<BufferData>,
+    swap_gain: f32, // fades the output out and in around a buffer swap
+    seed: u64,
+    lock_randomness: bool, // reseeds and clears the state whenever the transport starts
+    midi_mode: MidiMode,
+    midi_play_head: usize,
+    root_note: u8,     // note played without transposition
+    burst_size: usize, // grains spawned by every note
+    held_notes: midi::HeldNotes,
+    lfos: Vec<lfo::Lfo>,
 }
 
 impl Delay {
-    pub fn new(draw_data: Input<DrawData>) -> Self {
+    pub fn new(draw_data: Arc<DrawData>) -> Self {
         Self {
             buffer: Buffer {
                 data: vec![(0.0, 0.0); 1024],
                 write_head: 0,
+                length_sec: 0.0,
+            },
+            sidechain: Buffer {
+                data: vec![(0.0, 0.0); 1024],
+                write_head: 0,
+                length_sec: 0.0,
             },
             draw_data,
             sample_rate: 0.0,
@@ -107,56 +148,320 @@ impl Delay {
             play_heads: (0..PLAY_HEADS)
                 .map(|_| playhead::PlayHead::new(0.5, GRAIN_NUM))
                 .collect(),
-            filter: filter::StereoBiquadLowPass::new(5000.0, 48_000.0, 0.707),
+            filter: filter::StereoFilter::new(48_000.0),
+            filter_settings: (FilterMode::Lowpass, 0.5, 0.707),
+            saturator: saturator::Saturator::new(),
+            limiter: limiter::Limiter::new(),
             feedback_sample: (0.0, 0.0),
+            feedback_mod: 0.0,
             dry: 1.0,
             wet: 1.0,
-            draw_buffer: DrawBuffer::default(),
+            draw_buffer: draw::DrawBuffer::default(),
+            windows: window::WindowTables::new(),
+            sinc: interpolation::SincTable::new(),
+            quality: Quality::Normal,
+            tempo: 120.0,
+            playing: false,
+            beat_pos: None,
+            pan_law: PanLaw::Linear,
+            stereo_mode: StereoMode::LeftRight,
+            input_channels: 2,
+            freeze: false,
+            freeze_amount: 0.0,
+            pending_buffer: None,
+            old_buffer: None,
+            swap_gain: 1.0,
+            seed: 0,
+            lock_randomness: false,
+            midi_mode: MidiMode::Off,
+            midi_play_head: 0,
+            root_note: 60,
+            burst_size: 0,
+            held_notes: midi::HeldNotes::new(),
+            lfos: (0..LFO_NUM).map(|_| lfo::Lfo::new()).collect(),
         }
     }
 
-    pub fn init(&mut self, sample_rate: f32) {
-        let buffer_length = (BUFFER_SIZE_SEC * sample_rate) as usize;
+    pub fn init(&mut self, sample_rate: f32, buffer_length_sec: f32) {
+        let buffer_length = (buffer_length_sec * sample_rate) as usize;
 
-        self.buffer.data.resize(buffer_length, (0.0, 0.0));
+        for buffer in [&mut self.buffer, &mut self.sidechain] {
+            buffer.data.resize(buffer_length, (0.0, 0.0));
+            buffer.write_head %= buffer_length;
+            buffer.length_sec = buffer_length_sec;
+        }
         self.filter.update_sample_rate(sample_rate);
+        self.limiter.update_sample_rate(sample_rate);
 
         self.sample_rate = sample_rate;
         self.play_heads.iter_mut().for_each(|play_head| {
-            play_head.init(sample_rate, BUFFER_SIZE_SEC);
+            play_head.init(sample_rate, buffer_length_sec);
         });
+        self.lfos
+            .iter_mut()
+            .for_each(|lfo| lfo.set_sample_rate(sample_rate));
+        self.reseed();
+
+        self.draw_buffer
+            .init(&self.draw_data, sample_rate, buffer_length);
+    }
+
+    // data is allocated off the audio thread and replaces the buffer once it holds the most recent
+    // samples and the output faded out
+    pub fn set_buffer(&mut self, data: BufferData, length_sec: f32) {
+        let length = data.main.len();
+        // the samples taken over end right before the write head
+        let write_head = self.buffer.data.len().min(length) % length;
+        self.pending_buffer = Some(PendingBuffer {
+            data,
+            length_sec,
+            write_head,
+            written: 0,
+            copied: 0,
+        });
+    }
+
+    // the replaced buffer, so it can be dropped off the audio thread
+    pub fn take_old_buffer(&mut self) -> Option<BufferData> {
+        self.old_buffer.take()
+    }
+
+    fn swap_buffer(&mut self) {
+        if let Some(PendingBuffer {
+            data,
+            length_sec,
+            write_head,
+            ..
+        }) = self.pending_buffer.take()
+        {
+            let buffer_length = data.main.len();
+            self.old_buffer = Some(BufferData {
+                main: std::mem::replace(&mut self.buffer.data, data.main),
+                sidechain: std::mem::replace(&mut self.sidechain.data, data.sidechain),
+            });
+            for buffer in [&mut self.buffer, &mut self.sidechain] {
+                buffer.write_head = write_head;
+                buffer.length_sec = length_sec;
+            }
+
+            let sample_rate = self.sample_rate;
+            self.play_heads.iter_mut().for_each(|play_head| {
+                play_head.init(sample_rate, length_sec);
+            });
+
+            self.draw_buffer.set_buffer_length(buffer_length);
+        }
+    }
+
+    // copies the next chunk of the most recent samples into the pending buffer, counted back from
+    // the write head. new samples are written to both buffers, so the copy is complete once both
+    // together cover the shorter buffer. returns whether a pending buffer is complete
+    fn fill_pending_buffer(&mut self) -> bool {
+        let Some(pending) = self.pending_buffer.as_mut() else {
+            return false;
+        };
+        let length = self.buffer.data.len();
+        let pending_length = pending.data.main.len();
+        let kept = length.min(pending_length);
+        let count = SWAP_COPY_CHUNK.min(kept.saturating_sub(pending.written + pending.copied));
+
+        for _ in 0..count {
+            // age of the sample, 1 is the last one written
+            let age = pending.written + pending.copied + 1;
+            let from = (self.buffer.write_head + length - age) % length;
+            let to = (pending.write_head + pending_length - age) % pending_length;
+            pending.data.main[to] = self.buffer.data[from];
+            pending.data.sidechain[to] = self.sidechain.data[from];
+            pending.copied += 1;
+        }
+        pending.written + pending.copied >= kept
+    }
+
+    fn update_swap_gain(&mut self) {
+        let fade_step = 1.0 / (SWAP_FADE_SEC * self.sample_rate);
+        if self.fill_pending_buffer() {
+            self.swap_gain = (self.swap_gain - fade_step).max(0.0);
+            if self.swap_gain == 0.0 {
+                self.swap_buffer();
+            }
+        } else {
+            self.swap_gain = (self.swap_gain + fade_step).min(1.0);
+        }
+    }
 
-        self.draw_buffer.samples_per_bar = buffer_length / BAR_NUM;
-        self.draw_buffer.data = vec![0.0; BAR_NUM];
+    pub fn set_enabled(&mut self, index: usize, value: bool) {
+        self.play_heads[index].enabled = value;
     }
 
     pub fn set_gain(&mut self, index: usize, value: f32) {
         self.play_heads[index].set_gain(value);
     }
 
-    pub fn set_pitch(&mut self, index: usize, value: i32) {
+    pub fn set_reverse(&mut self, index: usize, value: f32) {
+        self.play_heads[index].set_reverse(value);
+    }
+
+    pub fn set_pitch(&mut self, index: usize, value: f32) {
         self.play_heads[index].set_pitch(value);
     }
 
+    pub fn set_pitch_spread(&mut self, index: usize, value: f32) {
+        self.play_heads[index].set_pitch_spread(value);
+    }
+
+    pub fn set_grain_num(&mut self, index: usize, value: usize) {
+        self.play_heads[index].set_grain_num(value);
+    }
+
+    pub fn set_stealing(&mut self, index: usize, value: Stealing) {
+        self.play_heads[index].set_stealing(value);
+    }
+
+    pub fn set_source(&mut self, index: usize, value: Source) {
+        self.play_heads[index].set_source(value);
+    }
+
+    pub fn set_feedback_src(&mut self, index: usize, value: FeedbackSrc) {
+        self.play_heads[index].set_feedback_src(value);
+    }
+
+    pub fn set_spread(&mut self, index: usize, value: f32) {
+        self.play_heads[index].set_spread(value);
+    }
+
+    pub fn set_pan(&mut self, index: usize, value: f32) {
+        self.play_heads[index].set_pan(value);
+    }
+
+    pub fn set_stereo_mode(&mut self, stereo_mode: StereoMode) {
+        self.stereo_mode = stereo_mode;
+        self.play_heads
+            .iter_mut()
+            .for_each(|play_head| play_head.set_split(stereo_mode == StereoMode::MidSide));
+    }
+
+    pub fn set_input_channels(&mut self, input_channels: usize) {
+        self.input_channels = input_channels;
+    }
+
+    pub fn set_pan_law(&mut self, value: PanLaw) {
+        self.pan_law = value;
+    }
+
+    pub fn set_harmony(&mut self, index: usize, value: Harmony) {
+        self.play_heads[index].set_harmony(value);
+    }
+
     pub fn set_distance(&mut self, index: usize, value: f32) {
         self.play_heads[index].set_distance(value);
     }
 
+    pub fn set_distance_synced(&mut self, index: usize, value: NoteDivision) {
+        self.play_heads[index].set_distance_synced(value.beats(), self.tempo);
+    }
+
     pub fn set_density(&mut self, index: usize, value: f32) {
         self.play_heads[index].set_density(value);
     }
 
+    pub fn set_density_synced(&mut self, index: usize, value: NoteDivision) {
+        self.play_heads[index].set_density_synced(value.beats(), self.tempo, self.beat_pos);
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        if seed != self.seed {
+            self.seed = seed;
+            self.reseed();
+        }
+    }
+
+    pub fn set_lock_randomness(&mut self, lock_randomness: bool) {
+        self.lock_randomness = lock_randomness;
+    }
+
+    // every playhead and lfo gets its own sequence, so enabling one does not shift the others
+    fn reseed(&mut self) {
+        let seed = self.seed;
+        self.play_heads
+            .iter_mut()
+            .enumerate()
+            .for_each(|(index, play_head)| play_head.set_seed(seed ^ ((index as u64) << 32)));
+        self.lfos
+            .iter_mut()
+            .enumerate()
+            .for_each(|(index, lfo)| lfo.set_seed(seed ^ (((PLAY_HEADS + index) as u64) << 32)));
+    }
+
+    // clears everything that carries over from the last playback, so that together with the
+    // reseed every playback from the same position renders the same output. clearing the buffers
+    // is a pass over the whole buffer length, but only happens when a locked playback starts. a
+    // frozen buffer is kept, it is what the grains are meant to play
+    fn reset(&mut self) {
+        if !self.freeze {
+            for buffer in [&mut self.buffer, &mut self.sidechain] {
+                buffer.data.fill((0.0, 0.0));
+                buffer.write_head = 0;
+            }
+            // a pending buffer is cleared as well, there is nothing left to copy
+            if let Some(pending) = self.pending_buffer.as_mut() {
+                pending.data.main.fill((0.0, 0.0));
+                pending.data.sidechain.fill((0.0, 0.0));
+                pending.copied = pending.data.main.len();
+            }
+        }
+        self.play_heads
+            .iter_mut()
+            .for_each(|play_head| play_head.reset());
+        self.filter.reset();
+        self.saturator.reset();
+        self.limiter.reset();
+        self.feedback_sample = (0.0, 0.0);
+        self.freeze_amount = if self.freeze { 1.0 } else { 0.0 };
+    }
+
+    // beat_pos is the song position in beats at the next sample, None while the transport is
+    // stopped
+    pub fn set_transport(&mut self, tempo: f32, playing: bool, beat_pos: Option<f64>) {
+        if self.lock_randomness && playing && !self.playing {
+            self.reseed();
+            self.reset();
+        }
+        self.playing = playing;
+        self.tempo = tempo;
+        self.beat_pos = beat_pos;
+    }
+
     pub fn set_window_size(&mut self, index: usize, value: f32) {
         self.play_heads[index].set_window_size(value);
     }
 
+    pub fn set_window_shape(&mut self, index: usize, value: WindowShape) {
+        self.play_heads[index].set_window_shape(value);
+    }
+
+    pub fn set_window_flat(&mut self, index: usize, value: f32) {
+        self.play_heads[index].set_window_flat(value);
+    }
+
     pub fn set_grain_size(&mut self, index: usize, value: f32) {
         self.play_heads[index].set_grain_size(value);
     }
 
-    pub fn set_cutoff(&mut self, value: f32) {
-        let cutoff = value * 10_000.0 + 100.0;
-        self.filter.update_coefficients(cutoff);
+    // the coefficients are updated once the lfos are applied
+    pub fn set_filter(&mut self, mode: FilterMode, color: f32, resonance: f32) {
+        self.filter_settings = (mode, color, resonance);
+    }
+
+    pub fn set_saturation(&mut self, mode: SaturationMode, drive: f32) {
+        self.saturator.set(mode, drive);
+    }
+
+    pub fn set_quality(&mut self, value: Quality) {
+        self.quality = value;
+    }
+
+    pub fn set_freeze(&mut self, value: bool) {
+        self.freeze = value;
     }
 
     pub fn set_dry(&mut self, value: f32) {
@@ -171,128 +476,497 @@ impl Delay {
         self.play_heads[index].set_chaos(value);
     }
 
-    pub fn get_draw_data(&mut self, sample: f32) {
-        let draw_data = self.draw_data.input_buffer();
+    pub fn set_trigger_mode(&mut self, index: usize, value: TriggerMode) {
+        self.play_heads[index].set_trigger_mode(value);
+    }
 
-        // grains
-        draw_data.grains.clear();
-        self.play_heads.iter().for_each(|play_head| {
-            play_head.grains.iter().for_each(|grain| {
-                if grain.active {
-                    draw_data.grains.push(Graindata {
-                        pos: 1.0 - grain.pos,
-                        stereo_pos: grain.stereo_pos,
-                        gain: grain.gain,
-                    })
-                }
-            })
-        });
+    pub fn set_lfo_shape(&mut self, index: usize, value: LfoShape) {
+        self.lfos[index].shape = value;
+    }
 
-        // waveform
-        self.draw_buffer.sample_count += 1;
-        self.draw_buffer.sample_sum += sample.abs();
-        if self.draw_buffer.sample_count >= self.draw_buffer.samples_per_bar {
-            let mut new_bar = self.draw_buffer.sample_sum / self.draw_buffer.samples_per_bar as f32;
+    pub fn set_lfo_target(&mut self, index: usize, target: LfoTarget, play_head: usize) {
+        self.lfos[index].target = target;
+        self.lfos[index].play_head = play_head.min(PLAY_HEADS - 1);
+    }
 
-            if new_bar > 1.0 {
-                new_bar = 1.0
-            }
+    pub fn set_lfo_rate(&mut self, index: usize, value: f32) {
+        self.lfos[index].set_rate(value);
+    }
 
-            self.draw_buffer.data.remove(0);
-            self.draw_buffer.data.push(new_bar);
-            draw_data.buffer = self.draw_buffer.data.clone();
+    pub fn set_lfo_rate_synced(&mut self, index: usize, value: NoteDivision) {
+        self.lfos[index].set_rate_synced(value.beats(), self.tempo, self.beat_pos);
+    }
 
-            self.draw_buffer.current_index = self.draw_buffer.current_index + 1;
+    pub fn set_lfo_depth(&mut self, index: usize, value: f32) {
+        self.lfos[index].depth = value;
+    }
 
-            if self.draw_buffer.current_index >= draw_data.buffer.len() {
-                self.draw_buffer.current_index = 0;
+    // advances the lfos and hands their sum to the playheads, the feedback and the filter
+    fn modulate(&mut self) {
+        let mut modulation = [lfo::Modulation::default(); PLAY_HEADS];
+        let mut feedback = 0.0;
+        let mut color = 0.0;
+        for lfo in self.lfos.iter_mut() {
+            let value = lfo.next() * lfo.depth;
+            let play_head = &mut modulation[lfo.play_head];
+            match lfo.target {
+                LfoTarget::Off => (),
+                LfoTarget::Distance => play_head.distance += value,
+                LfoTarget::WindowSize => play_head.window_size += value,
+                LfoTarget::GrainSize => play_head.grain_size += value,
+                LfoTarget::Density => play_head.density += value,
+                LfoTarget::Pitch => play_head.pitch += value,
+                LfoTarget::Gain => play_head.gain += value,
+                LfoTarget::Feedback => feedback += value,
+                LfoTarget::Color => color += value,
             }
+        }
+
+        self.play_heads
+            .iter_mut()
+            .zip(modulation)
+            .for_each(|(play_head, modulation)| play_head.set_modulation(modulation));
+        self.feedback_mod = feedback;
+        let (mode, base_color, resonance) = self.filter_settings;
+        self.filter
+            .update_coefficients(mode, (base_color + color).clamp(0.0, 1.0), resonance);
+    }
 
-            self.draw_buffer.sample_sum = 0.0;
-            self.draw_buffer.sample_count = 0;
-            self.draw_data.publish();
+    pub fn set_midi(&mut self, mode: MidiMode, index: usize, root_note: u8, burst_size: usize) {
+        self.midi_mode = mode;
+        self.midi_play_head = index;
+        self.root_note = root_note;
+        self.burst_size = burst_size;
+        self.update_note();
+    }
+
+    pub fn note_on(&mut self, channel: u8, note: u8, velocity: f32) {
+        self.held_notes.press(channel, note, velocity);
+        self.update_note();
+        if self.midi_mode != MidiMode::Off {
+            self.play_heads[self.midi_play_head].burst(self.burst_size);
         }
     }
 
-    fn write(&mut self, signal: (&f32, &f32)) {
+    pub fn note_off(&mut self, channel: u8, note: u8) {
+        self.held_notes.release(channel, note);
+        self.update_note();
+    }
+
+    // only the chosen playhead follows the keyboard, all others play untransposed
+    fn update_note(&mut self) {
+        let note = self
+            .held_notes
+            .last()
+            .map(|(note, velocity)| (note as f32 - self.root_note as f32, velocity));
+        for (index, play_head) in self.play_heads.iter_mut().enumerate() {
+            let selected = self.midi_mode != MidiMode::Off && index == self.midi_play_head;
+            play_head.set_note(if selected { note } else { None });
+            play_head.set_gated(selected && self.midi_mode == MidiMode::Gate);
+        }
+    }
+
+    // the bars are collected every sample, everything else is only published at display rate
+    fn update_draw_data(&mut self, sample: f32) {
+        self.draw_buffer.push(&self.draw_data, sample);
+        if !self.draw_buffer.snapshot_due() {
+            return;
+        }
+
+        let active_grains = self
+            .play_heads
+            .iter()
+            .flat_map(|play_head| play_head.grains.iter())
+            .filter(|grain| grain.active);
+        let mut grain_count = 0;
+        for (data, grain) in self.draw_data.grains.iter().zip(active_grains) {
+            data.pos.store(1.0 - grain.pos, Ordering::Relaxed);
+            data.stereo_pos.store(grain.stereo_pos, Ordering::Relaxed);
+            data.gain.store(grain.gain, Ordering::Relaxed);
+            grain_count += 1;
+        }
+        self.draw_data
+            .grain_count
+            .store(grain_count, Ordering::Relaxed);
+
+        for (data, play_head) in self.draw_data.play_heads.iter().zip(self.play_heads.iter()) {
+            data.distance
+                .store(play_head.modulated_distance(), Ordering::Relaxed);
+            data.enabled.store(play_head.enabled, Ordering::Relaxed);
+        }
+
+        for (data, lfo) in self.draw_data.lfos.iter().zip(self.lfos.iter()) {
+            data.store(lfo.value * lfo.depth, Ordering::Relaxed);
+        }
+
+        self.draw_data.frozen.store(self.freeze, Ordering::Relaxed);
+    }
+
+    fn write(&mut self, signal: (f32, f32), sidechain: (f32, f32)) {
+        let amount = (self.feedback + self.feedback_mod).clamp(0.0, 1.0);
         let feedback = (
-            self.feedback_sample.0 * self.feedback * 0.5,
-            self.feedback_sample.1 * self.feedback * 0.5,
+            self.feedback_sample.0 * amount * 0.5,
+            self.feedback_sample.1 * amount * 0.5,
         );
 
         let feedback = self.filter.process(feedback);
+        let feedback = self.saturator.process(feedback);
+        let feedback = self.limiter.process(feedback);
         let write_head = self.buffer.write_head;
 
-        let left = signal.0 + feedback.0;
-        let right = signal.1 + feedback.1;
+        // the write head keeps moving while frozen so the playheads scan the captured buffer
+        let fade_step = 1.0 / (FREEZE_FADE_SEC * self.sample_rate);
+        self.freeze_amount = if self.freeze {
+            (self.freeze_amount + fade_step).min(1.0)
+        } else {
+            (self.freeze_amount - fade_step).max(0.0)
+        };
+
+        let (old_left, old_right) = self.buffer.data[write_head];
+        let left = lerp(signal.0 + feedback.0, old_left, self.freeze_amount);
+        let right = lerp(signal.1 + feedback.1, old_right, self.freeze_amount);
 
         self.buffer.data[write_head].0 = left;
         self.buffer.data[write_head].1 = right;
 
+        // both buffers share the write head, so a distance means the same time for either source
+        let (old_left, old_right) = self.sidechain.data[write_head];
+        self.sidechain.data[write_head] = (
+            lerp(sidechain.0, old_left, self.freeze_amount),
+            lerp(sidechain.1, old_right, self.freeze_amount),
+        );
+
+        if let Some(pending) = self.pending_buffer.as_mut() {
+            pending.data.main[pending.write_head] = (left, right);
+            pending.data.sidechain[pending.write_head] = self.sidechain.data[write_head];
+            pending.write_head = (pending.write_head + 1) % pending.data.main.len();
+            pending.written += 1;
+        }
+
         self.buffer.write_head = (self.buffer.write_head + 1) % self.buffer.data.len();
+        self.sidechain.write_head = self.buffer.write_head;
 
-        self.get_draw_data(left + right);
+        self.update_draw_data(left + right);
     }
 
-    fn read(&mut self, signal: (&mut f32, &mut f32)) {
+    // returns the wet signal in left and right, the feedback stays in the channels of the buffer
+    fn read(&mut self) -> (f32, f32) {
         let mut out = (0.0, 0.0);
         let mut feedback = (0.0, 0.0);
         for play_head in self.play_heads.iter_mut() {
-            play_head.update();
+            // disabled playheads are skipped once their last grains have faded out
+            if !play_head.enabled && !play_head.has_active_grains() {
+                continue;
+            }
+
+            play_head.update(&self.windows);
 
-            let buffer_size = self.buffer.data.len() as f32;
+            let buffer = match play_head.source {
+                Source::Main => &self.buffer,
+                Source::Sidechain => &self.sidechain,
+            };
+            let buffer_size = buffer.data.len() as f32;
 
-            if play_head.feedback_src == playhead::FeedbackSrc::Playhead {
-                let offset = buffer_size * play_head.current_distance;
+            if play_head.enabled && play_head.feedback_src == FeedbackSrc::Playhead {
+                let offset = buffer_size * play_head.modulated_distance();
 
-                let mut feedback_pos = self.buffer.write_head as f32 - offset;
+                let mut feedback_pos = buffer.write_head as f32 - offset;
 
                 if feedback_pos < 0.0 {
                     feedback_pos += buffer_size;
                 }
 
-                feedback.0 += self.buffer.data[feedback_pos as usize % self.buffer.data.len()].0;
-                feedback.1 += self.buffer.data[feedback_pos as usize % self.buffer.data.len()].1;
+                let (left, right) = buffer.data[feedback_pos as usize % buffer.data.len()];
+                feedback.0 += left;
+                feedback.1 += right;
             }
 
-            let grain_data = play_head.get_grain_data();
-
-            grain_data.iter().for_each(|(pos, gain, stereo_pos)| {
-                let offset = buffer_size * pos;
+            // reads the grains in place, nothing may be allocated per sample
+            let write_head = buffer.write_head as f32;
+            let mut grain_feedback = (0.0, 0.0);
+            let mut active_grains = 0;
 
-                let mut read_pos = self.buffer.write_head as f32 - offset;
+            for grain in play_head.grains.iter().filter(|grain| grain.active) {
+                let mut read_pos = write_head - buffer_size * grain.pos;
 
                 if read_pos < 0.0 {
                     read_pos += buffer_size;
                 }
 
-                let left_gain = 0.5 * (1.0 - stereo_pos);
-                let right_gain = 0.5 * (1.0 + stereo_pos);
+                let (left_gain, right_gain) = self.pan_law.gains(grain.stereo_pos);
 
-                let (left_sample, right_sample) = self.buffer.get_cubic_sample(read_pos);
+                let (left_sample, right_sample) =
+                    buffer.get_sample(read_pos, self.quality, grain.ratio, &self.sinc);
+                // a grain of a split mid/side playhead only plays its own channel
+                let (left_sample, right_sample) = match grain.channels {
+                    Channels::Both => (left_sample, right_sample),
+                    Channels::Mid => (left_sample, 0.0),
+                    Channels::Side => (0.0, right_sample),
+                };
+                let left_sample = left_sample * grain.gain;
+                let right_sample = right_sample * grain.gain;
+
+                // windowed but unpanned so the stereo image of the loop is kept
+                grain_feedback.0 += left_sample;
+                grain_feedback.1 += right_sample;
+
+                // the buffer holds mid and side in mid/side mode, the pan law works on left and
+                // right
+                let (left_sample, right_sample) =
+                    self.stereo_mode.decode((left_sample, right_sample));
+                out.0 += left_sample * left_gain;
+                out.1 += right_sample * right_gain;
+                active_grains += 1;
+            }
 
-                // this seems not to work so just using FeedbackSrc::Playhead for now
-                if play_head.feedback_src == playhead::FeedbackSrc::Grain {
-                    feedback.0 += left_sample;
-                    feedback.1 += right_sample;
-                }
+            if play_head.feedback_src == FeedbackSrc::Grain {
+                // overlapping grains are mostly uncorrelated, so their sum grows with the square
+                // root of their number
+                let norm = (active_grains as f32).sqrt().max(1.0);
+                feedback.0 += grain_feedback.0 / norm;
+                feedback.1 += grain_feedback.1 / norm;
+            }
+        }
 
-                out.0 += left_sample * *gain * left_gain;
-                out.1 += right_sample * *gain * right_gain;
-            });
+        self.feedback_sample = (feedback.0 * self.swap_gain, feedback.1 * self.swap_gain);
+
+        (
+            out.0 * self.wet * self.swap_gain,
+            out.1 * self.wet * self.swap_gain,
+        )
+    }
+
+    // channels holds one sample per channel, the delay itself always runs in stereo. a mono
+    // input feeds both sides and a mono output gets the average of both
+    pub fn render(&mut self, channels: &mut [f32], sidechain: &[f32]) {
+        // follows the song position through the block, so aligning to the grid between two samples
+        // finds the same grid as at the start of the block
+        if let Some(beat_pos) = self.beat_pos.as_mut() {
+            *beat_pos += self.tempo as f64 / 60.0 / self.sample_rate as f64;
         }
 
-        self.feedback_sample = feedback;
+        self.update_swap_gain();
+        self.modulate();
+
+        let input = match channels {
+            [left, right, ..] if self.input_channels >= 2 => (*left, *right),
+            [mono, ..] => (*mono, *mono),
+            [] => return,
+        };
+        let sidechain = match sidechain {
+            [left, right, ..] => (*left, *right),
+            [mono] => (*mono, *mono),
+            [] => (0.0, 0.0),
+        };
+
+        self.write(
+            self.stereo_mode.encode(input),
+            self.stereo_mode.encode(sidechain),
+        );
+        let wet = self.read();
+        let left = input.0 * self.dry + wet.0;
+        let right = input.1 * self.dry + wet.1;
+
+        match channels {
+            [mono] => *mono = (left + right) * 0.5,
+            [out_left, out_right, ..] => {
+                *out_left = left;
+                *out_right = right;
+            }
+            [] => (),
+        }
+    }
+}
 
-        *signal.0 *= self.dry;
-        *signal.1 *= self.dry;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 8000.0;
+    const BLOCK_SIZE: usize = 64;
+
+    fn delay() -> Delay {
+        let mut delay = Delay::new(Arc::new(DrawData::new()));
+        delay.init(SAMPLE_RATE, 1.0);
+        for index in 0..2 {
+            delay.set_enabled(index, true);
+            delay.set_gain(index, 1.0);
+            delay.set_window_size(index, 0.5);
+            delay.set_distance(index, 0.1 + 0.2 * index as f32);
+            delay.set_density(index, 20.0);
+            delay.set_grain_size(index, 0.1);
+            delay.set_spread(index, 1.0);
+            delay.set_pitch_spread(index, 0.5);
+            delay.set_reverse(index, 0.5);
+            delay.set_chaos(index, 1.0);
+            delay.set_trigger_mode(index, TriggerMode::Poisson);
+        }
+        delay.feedback = 0.5;
+        // only the grains
+        delay.dry = 0.0;
+        delay.set_seed(42);
+        delay.set_lock_randomness(true);
+        delay
+    }
 
-        *signal.0 += out.0 * self.wet;
-        *signal.1 += out.1 * self.wet;
+    // stereo output of noise fed in for the given time, the input depends on the seed
+    fn render(delay: &mut Delay, seconds: f32, playing: bool, seed: u64) -> Vec<f32> {
+        let mut input = rng::Rng::new(seed);
+        let mut output = Vec::new();
+        let mut beat_pos = 0.0;
+        for _ in 0..(seconds * SAMPLE_RATE) as usize / BLOCK_SIZE {
+            delay.set_transport(120.0, playing, Some(beat_pos).filter(|_| playing));
+            for _ in 0..BLOCK_SIZE {
+                let mut frame = [input.next_bipolar(), input.next_bipolar()];
+                delay.render(&mut frame, &[]);
+                output.extend(frame);
+            }
+            beat_pos += 2.0 * BLOCK_SIZE as f64 / SAMPLE_RATE as f64;
+        }
+        output
     }
 
-    pub fn render(&mut self, samples: (&mut f32, &mut f32)) {
-        self.write((samples.0, samples.1));
-        self.read(samples);
+    #[test]
+    fn locked_playbacks_render_the_same() {
+        let mut delay = delay();
+        let first = render(&mut delay, 2.0, true, 1);
+        // stops in the middle of grains with a buffer full of different input
+        render(&mut delay, 0.77, false, 2);
+        let second = render(&mut delay, 2.0, true, 1);
+
+        assert!(first.iter().any(|sample| sample.abs() > 0.01));
+        assert!(first == second, "the second playback differs");
+    }
+
+    #[test]
+    fn locked_playback_keeps_a_frozen_buffer() {
+        let mut delay = delay();
+        render(&mut delay, 1.0, false, 1);
+        delay.set_freeze(true);
+        render(&mut delay, 0.1, false, 2);
+        let output = render(&mut delay, 0.5, true, 3);
+
+        // the grains play the frozen input, a cleared buffer would be silent
+        assert!(output.iter().any(|sample| sample.abs() > 0.01));
+    }
+
+    #[test]
+    fn mid_side_grains_are_panned_in_left_and_right() {
+        let mut delay = Delay::new(Arc::new(DrawData::new()));
+        delay.init(SAMPLE_RATE, 1.0);
+        delay.set_stereo_mode(StereoMode::MidSide);
+        delay.dry = 0.0;
+        delay.set_enabled(0, true);
+        delay.set_gain(0, 1.0);
+        delay.set_window_size(0, 0.5);
+        delay.set_distance(0, 0.1);
+        delay.set_density(0, 20.0);
+        delay.set_grain_size(0, 0.1);
+        delay.set_spread(0, 0.0);
+        delay.set_pan(0, 1.0);
+
+        // the same signal on both sides has no side, only the mid grains sound
+        let mut input = rng::Rng::new(1);
+        let mut output = Vec::new();
+        for _ in 0..SAMPLE_RATE as usize {
+            let sample = input.next_bipolar();
+            let mut frame = [sample, sample];
+            delay.render(&mut frame, &[]);
+            output.push(frame);
+        }
+
+        assert!(output.iter().any(|[_, right]| right.abs() > 0.01));
+        assert!(output.iter().all(|[left, _]| left.abs() < 1e-6));
+    }
+
+    // renders a ramp until a new buffer of the given length replaced the current one
+    fn swap_buffer(length_sec: f32) -> (Delay, usize) {
+        let mut delay = Delay::new(Arc::new(DrawData::new()));
+        delay.init(SAMPLE_RATE, 1.0);
+        delay.feedback = 0.0;
+        let mut count = 0;
+        let mut render = |delay: &mut Delay, samples: usize| {
+            for _ in 0..samples {
+                count += 1;
+                delay.render(&mut [count as f32, -(count as f32)], &[]);
+            }
+        };
+        render(&mut delay, 12_000);
+        let length = (length_sec * SAMPLE_RATE) as usize;
+        delay.set_buffer(BufferData::new(length), length_sec);
+        render(&mut delay, 1000);
+
+        assert!(delay.pending_buffer.is_none());
+        assert_eq!(delay.buffer.data.len(), length);
+        assert!(delay.take_old_buffer().is_some());
+        (delay, count)
+    }
+
+    // the samples of the ramp up to the given age, older samples are either empty or still match
+    fn assert_recent_samples(delay: &Delay, count: usize, kept: usize) {
+        let length = delay.buffer.data.len();
+        for age in 1..=length {
+            let index = (delay.buffer.write_head + length - age) % length;
+            let value = count as f32 + 1.0 - age as f32;
+            let sample = delay.buffer.data[index];
+            assert!(
+                sample == (value, -value) || age > kept && sample == (0.0, 0.0),
+                "sample of age {age} is {sample:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn shorter_buffer_keeps_the_most_recent_samples() {
+        let (delay, count) = swap_buffer(0.5);
+        assert_recent_samples(&delay, count, 4000);
+    }
+
+    #[test]
+    fn longer_buffer_keeps_the_whole_buffer() {
+        let (delay, count) = swap_buffer(2.0);
+        assert_recent_samples(&delay, count, 8000);
+    }
+
+    // cpu time of mixing full grain pools, run with
+    // cargo test --release mixing_full_grain_pools -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn mixing_full_grain_pools() {
+        let sample_rate = 48_000.0;
+        let mut delay = Delay::new(Arc::new(DrawData::new()));
+        delay.init(sample_rate, 2.0);
+        for index in 0..2 {
+            delay.set_enabled(index, true);
+            delay.set_gain(index, 1.0);
+            delay.set_window_size(index, 0.5);
+            delay.set_distance(index, 0.5);
+            delay.set_grain_num(index, GRAIN_NUM);
+            delay.set_density(index, 400.0);
+            delay.set_grain_size(index, 1.0);
+            delay.set_spread(index, 1.0);
+        }
+        let mut input = rng::Rng::new(1);
+        let mut render = |delay: &mut Delay, samples: usize| {
+            for _ in 0..samples {
+                let mut frame = [input.next_bipolar(), input.next_bipolar()];
+                delay.render(&mut frame, &[]);
+            }
+        };
+        // fills the grain pools
+        render(&mut delay, sample_rate as usize);
+        let active = delay
+            .play_heads
+            .iter()
+            .flat_map(|play_head| play_head.grains.iter())
+            .filter(|grain| grain.active)
+            .count();
+        assert_eq!(active, 2 * GRAIN_NUM);
+
+        let samples = 10 * sample_rate as usize;
+        let start = std::time::Instant::now();
+        render(&mut delay, samples);
+        let elapsed = start.elapsed().as_nanos() as f64 / samples as f64;
+        eprintln!("{active} grains: {elapsed:.0} ns per sample");
     }
 }
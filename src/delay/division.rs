@@ -0,0 +1,96 @@
+use nih_plug::prelude::Enum;
+
+// variants are ordered from long to short in groups of straight, dotted and triplet
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum NoteDivision {
+    #[name = "1/1"]
+    Whole,
+    #[name = "1/1."]
+    WholeDotted,
+    #[name = "1/1T"]
+    WholeTriplet,
+    #[name = "1/2"]
+    Half,
+    #[name = "1/2."]
+    HalfDotted,
+    #[name = "1/2T"]
+    HalfTriplet,
+    #[name = "1/4"]
+    Quarter,
+    #[name = "1/4."]
+    QuarterDotted,
+    #[name = "1/4T"]
+    QuarterTriplet,
+    #[name = "1/8"]
+    Eighth,
+    #[name = "1/8."]
+    EighthDotted,
+    #[name = "1/8T"]
+    EighthTriplet,
+    #[name = "1/16"]
+    Sixteenth,
+    #[name = "1/16."]
+    SixteenthDotted,
+    #[name = "1/16T"]
+    SixteenthTriplet,
+    #[name = "1/32"]
+    ThirtySecond,
+    #[name = "1/32."]
+    ThirtySecondDotted,
+    #[name = "1/32T"]
+    ThirtySecondTriplet,
+    #[name = "1/64"]
+    SixtyFourth,
+    #[name = "1/64."]
+    SixtyFourthDotted,
+    #[name = "1/64T"]
+    SixtyFourthTriplet,
+}
+
+impl NoteDivision {
+    // length in quarter note beats
+    pub fn beats(self) -> f32 {
+        let index = self.to_index();
+        let straight = 4.0 / (1 << (index / 3)) as f32;
+        match index % 3 {
+            0 => straight,
+            1 => straight * 1.5,
+            _ => straight * 2.0 / 3.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn beats() {
+        let expected = [
+            (NoteDivision::Whole, 4.0),
+            (NoteDivision::WholeDotted, 6.0),
+            (NoteDivision::WholeTriplet, 8.0 / 3.0),
+            (NoteDivision::Half, 2.0),
+            (NoteDivision::Quarter, 1.0),
+            (NoteDivision::QuarterDotted, 1.5),
+            (NoteDivision::QuarterTriplet, 2.0 / 3.0),
+            (NoteDivision::Eighth, 0.5),
+            (NoteDivision::SixteenthTriplet, 1.0 / 6.0),
+            (NoteDivision::SixtyFourth, 1.0 / 16.0),
+            (NoteDivision::SixtyFourthDotted, 3.0 / 32.0),
+            (NoteDivision::SixtyFourthTriplet, 1.0 / 24.0),
+        ];
+        for (division, beats) in expected {
+            assert!((division.beats() - beats).abs() < 1e-6, "{division:?}");
+        }
+    }
+
+    #[test]
+    fn every_group_halves() {
+        for index in 3..NoteDivision::variants().len() {
+            let longer = NoteDivision::from_index(index - 3).beats();
+            let shorter = NoteDivision::from_index(index).beats();
+            assert!((longer / shorter - 2.0).abs() < 1e-6);
+        }
+    }
+}
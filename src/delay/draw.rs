@@ -0,0 +1,109 @@
+use nih_plug::prelude::AtomicF32;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use super::lfo::LFO_NUM;
+use super::playhead::RELEASE_SLOTS;
+use super::{BAR_NUM, GRAIN_NUM, PLAY_HEADS};
+
+const DRAW_RATE: f32 = 60.0; // snapshots per second
+
+// shared with the editor, every field is atomic so neither side ever waits for the other
+pub struct DrawData {
+    pub bars: Vec<AtomicF32>, // ring of average levels, bar_index points at the oldest one
+    pub bar_index: AtomicUsize,
+    pub grains: Vec<Graindata>,
+    pub grain_count: AtomicUsize, // number of grains in use from the start of grains
+    pub play_heads: Vec<Playheaddata>,
+    pub lfos: Vec<AtomicF32>, // output of every lfo after depth range -1 to 1
+    pub frozen: AtomicBool,
+}
+
+impl DrawData {
+    pub fn new() -> Self {
+        Self {
+            bars: (0..BAR_NUM).map(|_| AtomicF32::new(0.0)).collect(),
+            bar_index: AtomicUsize::new(0),
+            grains: (0..(GRAIN_NUM + RELEASE_SLOTS) * PLAY_HEADS)
+                .map(|_| Graindata {
+                    pos: AtomicF32::new(0.0),
+                    stereo_pos: AtomicF32::new(0.0),
+                    gain: AtomicF32::new(0.0),
+                })
+                .collect(),
+            grain_count: AtomicUsize::new(0),
+            play_heads: (0..PLAY_HEADS)
+                .map(|_| Playheaddata {
+                    distance: AtomicF32::new(0.0),
+                    enabled: AtomicBool::new(false),
+                })
+                .collect(),
+            lfos: (0..LFO_NUM).map(|_| AtomicF32::new(0.0)).collect(),
+            frozen: AtomicBool::new(false),
+        }
+    }
+}
+
+pub struct Graindata {
+    pub pos: AtomicF32,
+    pub stereo_pos: AtomicF32,
+    pub gain: AtomicF32,
+}
+
+pub struct Playheaddata {
+    pub distance: AtomicF32,
+    pub enabled: AtomicBool,
+}
+
+// collects the waveform bars and decides when the next snapshot is due
+#[derive(Default)]
+pub struct DrawBuffer {
+    sample_sum: f32,
+    sample_count: usize,
+    samples_per_bar: usize,
+    bar_index: usize,
+    snapshot_count: usize,
+    snapshot_interval: usize,
+}
+
+impl DrawBuffer {
+    pub fn init(&mut self, draw_data: &DrawData, sample_rate: f32, buffer_length: usize) {
+        self.snapshot_interval = (sample_rate / DRAW_RATE) as usize;
+        self.set_buffer_length(buffer_length);
+
+        self.bar_index = 0;
+        draw_data
+            .bars
+            .iter()
+            .for_each(|bar| bar.store(0.0, Ordering::Relaxed));
+        draw_data.bar_index.store(0, Ordering::Relaxed);
+    }
+
+    pub fn set_buffer_length(&mut self, buffer_length: usize) {
+        self.samples_per_bar = (buffer_length / BAR_NUM).max(1);
+    }
+
+    pub fn push(&mut self, draw_data: &DrawData, sample: f32) {
+        self.sample_count += 1;
+        self.sample_sum += sample.abs();
+        if self.sample_count >= self.samples_per_bar {
+            let new_bar = (self.sample_sum / self.samples_per_bar as f32).min(1.0);
+
+            // overwrites the oldest bar instead of shifting the whole history
+            draw_data.bars[self.bar_index].store(new_bar, Ordering::Relaxed);
+            self.bar_index = (self.bar_index + 1) % BAR_NUM;
+            draw_data.bar_index.store(self.bar_index, Ordering::Relaxed);
+
+            self.sample_sum = 0.0;
+            self.sample_count = 0;
+        }
+    }
+
+    pub fn snapshot_due(&mut self) -> bool {
+        self.snapshot_count += 1;
+        if self.snapshot_count >= self.snapshot_interval {
+            self.snapshot_count = 0;
+            return true;
+        }
+        false
+    }
+}
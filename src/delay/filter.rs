@@ -1,4 +1,24 @@
-pub struct BiquadLowPass {
+use nih_plug::prelude::Enum;
+
+const MIN_CUTOFF: f32 = 20.0;
+const MAX_CUTOFF: f32 = 20_000.0;
+const TILT_PIVOT: f32 = 1_000.0;
+const TILT_MAX_DB: f32 = 12.0;
+const COLOR_MAX_HIGHPASS: f32 = 0.7; // highest highpass cutoff of Color, about 2.5 kHz
+
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FilterMode {
+    Lowpass,
+    Highpass,
+    Bandpass,
+    Notch,
+    Tilt,
+    // lowpass below the center of color, highpass above it
+    #[name = "Dark/Bright"]
+    Color,
+}
+
+pub struct Biquad {
     b0: f32,
     b1: f32,
     b2: f32,
@@ -7,11 +27,10 @@ pub struct BiquadLowPass {
     z1: f32,
     z2: f32,
     sample_rate: f32,
-    q: f32,
 }
 
-impl BiquadLowPass {
-    pub fn new(cutoff: f32, sample_rate: f32, q: f32) -> Self {
+impl Biquad {
+    pub fn new(sample_rate: f32) -> Self {
         let mut filter = Self {
             b0: 0.0,
             b1: 0.0,
@@ -21,73 +40,156 @@ impl BiquadLowPass {
             z1: 0.0,
             z2: 0.0,
             sample_rate,
-            q,
         };
-        filter.update_coefficients(cutoff);
+        filter.update_coefficients(FilterMode::Lowpass, 5000.0, 0.707, 0.0);
         filter
     }
 
-    pub fn update_coefficients(&mut self, cutoff: f32) {
+    // coefficients after the RBJ audio eq cookbook, gain_db is only used by the shelf of Tilt
+    pub fn update_coefficients(&mut self, mode: FilterMode, cutoff: f32, q: f32, gain_db: f32) {
+        let cutoff = cutoff.clamp(MIN_CUTOFF, 0.49 * self.sample_rate);
         let omega = 2.0 * std::f32::consts::PI * cutoff / self.sample_rate;
-        let alpha = omega.sin() / (2.0 * self.q);
+        let alpha = omega.sin() / (2.0 * q);
         let cos_omega = omega.cos();
 
-        self.b0 = (1.0 - cos_omega) / 2.0;
-        self.b1 = 1.0 - cos_omega;
-        self.b2 = (1.0 - cos_omega) / 2.0;
-        let a0 = 1.0 + alpha;
-        self.a1 = -2.0 * cos_omega / a0;
-        self.a2 = (1.0 - alpha) / a0;
-
-        // Normalize feedforward coefficients
-        self.b0 /= a0;
-        self.b1 /= a0;
-        self.b2 /= a0;
+        let (b0, b1, b2, a0, a1, a2) = match mode {
+            FilterMode::Lowpass | FilterMode::Color => (
+                (1.0 - cos_omega) / 2.0,
+                1.0 - cos_omega,
+                (1.0 - cos_omega) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos_omega,
+                1.0 - alpha,
+            ),
+            FilterMode::Highpass => (
+                (1.0 + cos_omega) / 2.0,
+                -(1.0 + cos_omega),
+                (1.0 + cos_omega) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos_omega,
+                1.0 - alpha,
+            ),
+            FilterMode::Bandpass => (
+                alpha,
+                0.0,
+                -alpha,
+                1.0 + alpha,
+                -2.0 * cos_omega,
+                1.0 - alpha,
+            ),
+            FilterMode::Notch => (
+                1.0,
+                -2.0 * cos_omega,
+                1.0,
+                1.0 + alpha,
+                -2.0 * cos_omega,
+                1.0 - alpha,
+            ),
+            FilterMode::Tilt => {
+                // high shelf
+                let a = 10.0f32.powf(gain_db / 40.0);
+                let sqrt_a_alpha = 2.0 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.0) + (a - 1.0) * cos_omega + sqrt_a_alpha),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos_omega),
+                    a * ((a + 1.0) + (a - 1.0) * cos_omega - sqrt_a_alpha),
+                    (a + 1.0) - (a - 1.0) * cos_omega + sqrt_a_alpha,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos_omega),
+                    (a + 1.0) - (a - 1.0) * cos_omega - sqrt_a_alpha,
+                )
+            }
+        };
+
+        // Normalize coefficients
+        self.b0 = b0 / a0;
+        self.b1 = b1 / a0;
+        self.b2 = b2 / a0;
+        self.a1 = a1 / a0;
+        self.a2 = a2 / a0;
     }
 
-    pub fn update_sample_rate(&mut self, sample_rate:f32){
+    pub fn update_sample_rate(&mut self, sample_rate: f32) {
         self.sample_rate = sample_rate;
-        self.update_coefficients(5000.0);
+        self.update_coefficients(FilterMode::Lowpass, 5000.0, 0.707, 0.0);
+    }
+
+    pub fn reset(&mut self) {
+        self.z1 = 0.0;
+        self.z2 = 0.0;
     }
 
+    // transposed direct form II
     pub fn process(&mut self, input: f32) -> f32 {
-        let output = self.b0 * input + self.b1 * self.z1 + self.b2 * self.z2
-            - self.a1 * self.z1
-            - self.a2 * self.z2;
-        self.z2 = self.z1;
-        self.z1 = output;
+        let output = self.b0 * input + self.z1;
+        self.z1 = self.b1 * input - self.a1 * output + self.z2;
+        self.z2 = self.b2 * input - self.a2 * output;
         output
     }
 }
 
-pub struct StereoBiquadLowPass {
-    filters: (BiquadLowPass, BiquadLowPass),
+pub struct StereoFilter {
+    filters: (Biquad, Biquad),
+    output_gain: f32, // keeps the pivot of Tilt at unity gain
+    settings: Option<(FilterMode, f32, f32)>, // mode, color and q of the current coefficients
 }
 
-impl StereoBiquadLowPass {
-    pub fn new(cutoff: f32, sample_rate: f32, q: f32) -> Self {
-        let filter_l = BiquadLowPass::new(cutoff, sample_rate, q);
-        let filter_r = BiquadLowPass::new(cutoff, sample_rate, q);
-
+impl StereoFilter {
+    pub fn new(sample_rate: f32) -> Self {
         Self {
-            filters: (filter_l, filter_r),
+            filters: (Biquad::new(sample_rate), Biquad::new(sample_rate)),
+            output_gain: 1.0,
+            settings: None,
         }
     }
 
-    pub fn update_coefficients(&mut self, cutoff: f32) {
-        self.filters.0.update_coefficients(cutoff);
-        self.filters.1.update_coefficients(cutoff);
+    // color range 0-1, it sets the cutoff or for Tilt and Color how dark or bright the filter is
+    pub fn update_coefficients(&mut self, mode: FilterMode, color: f32, q: f32) {
+        // called every sample, so the coefficients are only recomputed once something changed
+        if self.settings == Some((mode, color, q)) {
+            return;
+        }
+        self.settings = Some((mode, color, q));
+        self.output_gain = 1.0;
+
+        let (mode, cutoff, gain_db) = match mode {
+            FilterMode::Tilt => {
+                let gain_db = (color * 2.0 - 1.0) * TILT_MAX_DB;
+                self.output_gain = 10.0f32.powf(-gain_db / 40.0);
+                (mode, TILT_PIVOT, gain_db)
+            }
+            FilterMode::Color if color < 0.5 => (FilterMode::Lowpass, exp_cutoff(color * 2.0), 0.0),
+            FilterMode::Color => (
+                FilterMode::Highpass,
+                exp_cutoff((color - 0.5) * 2.0 * COLOR_MAX_HIGHPASS),
+                0.0,
+            ),
+            _ => (mode, color * 10_000.0 + 100.0, 0.0),
+        };
+
+        self.filters.0.update_coefficients(mode, cutoff, q, gain_db);
+        self.filters.1.update_coefficients(mode, cutoff, q, gain_db);
     }
 
-    pub fn update_sample_rate(&mut self, sample_rate: f32){
+    pub fn update_sample_rate(&mut self, sample_rate: f32) {
         self.filters.0.update_sample_rate(sample_rate);
         self.filters.1.update_sample_rate(sample_rate);
+        self.settings = None;
+    }
+
+    pub fn reset(&mut self) {
+        self.filters.0.reset();
+        self.filters.1.reset();
     }
 
     pub fn process(&mut self, input: (f32, f32)) -> (f32, f32) {
         (
-            self.filters.0.process(input.0),
-            self.filters.1.process(input.1),
+            self.filters.0.process(input.0) * self.output_gain,
+            self.filters.1.process(input.1) * self.output_gain,
         )
     }
 }
+
+// value range 0-1 mapped exponentially onto the audible range
+fn exp_cutoff(value: f32) -> f32 {
+    MIN_CUTOFF * (MAX_CUTOFF / MIN_CUTOFF).powf(value)
+}
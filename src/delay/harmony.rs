@@ -0,0 +1,211 @@
+use super::rng::Rng;
+use nih_plug::prelude::Enum;
+
+const NOTE_NAMES: [&str; 12] = [
+    "1", "b2", "2", "b3", "3", "4", "b5", "5", "b6", "6", "b7", "7",
+];
+// relative probability of every interval at full weighting, consonant intervals come up more often
+const DEGREE_WEIGHTS: [f32; 12] = [1.0, 0.1, 0.3, 0.5, 0.5, 0.4, 0.1, 0.8, 0.3, 0.4, 0.3, 0.2];
+
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Scale {
+    Off,
+    Octaves,
+    Fifths,
+    Major,
+    Minor,
+    Pentatonic,
+    Custom,
+}
+
+impl Scale {
+    // intervals within one octave as a bitmask, bit 0 is the root
+    fn mask(self, custom: u16) -> u16 {
+        match self {
+            Scale::Off | Scale::Octaves => 0b0000_0000_0001,
+            Scale::Fifths => 0b0000_1000_0001,
+            Scale::Major => 0b1010_1011_0101,
+            Scale::Minor => 0b0101_1010_1101,
+            Scale::Pentatonic => 0b0010_1001_0101,
+            Scale::Custom => custom,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Harmony {
+    pub scale: Scale,
+    pub custom: u16,    // bitmask of the intervals used by Scale::Custom
+    pub octaves: u32,   // grains are spread up to this many octaves upwards
+    pub amount: f32,    // probability of a grain being transposed range 0-1
+    pub weighting: f32, // 0 picks every interval equally often, 1 follows DEGREE_WEIGHTS
+}
+
+impl Harmony {
+    pub fn new() -> Self {
+        Self {
+            scale: Scale::Off,
+            custom: 0,
+            octaves: 1,
+            amount: 1.0,
+            weighting: 1.0,
+        }
+    }
+
+    // random transposition in semitones
+    pub fn pick(&self, rng: &mut Rng) -> f32 {
+        if self.scale == Scale::Off || rng.next_f32() >= self.amount {
+            return 0.0;
+        }
+
+        let mask = self.scale.mask(self.custom);
+        let intervals = || (0..12).filter(move |interval| mask & (1 << interval) != 0);
+        let total: f32 = intervals().map(|interval| self.weight(interval)).sum();
+        if total <= 0.0 {
+            return 0.0;
+        }
+
+        // walks the intervals until the random point falls into the weight of one of them
+        let mut point = rng.next_f32() * total;
+        let interval = intervals()
+            .find(|interval| {
+                point -= self.weight(*interval);
+                point < 0.0
+            })
+            .or_else(|| intervals().next_back())
+            .unwrap_or(0);
+        let octave = rng.next_u32() % (self.octaves + 1);
+
+        (interval + 12 * octave) as f32
+    }
+
+    fn weight(&self, interval: u32) -> f32 {
+        1.0 + (DEGREE_WEIGHTS[interval as usize] - 1.0) * self.weighting
+    }
+}
+
+pub fn mask_to_string(mask: i32) -> String {
+    let names: Vec<&str> = (0..12)
+        .filter(|interval| mask & (1 << interval) != 0)
+        .map(|interval| NOTE_NAMES[interval])
+        .collect();
+
+    if names.is_empty() {
+        String::from("-")
+    } else {
+        names.join(" ")
+    }
+}
+
+// accepts interval names separated by spaces or commas, e.g. "1 b3 5"
+pub fn string_to_mask(string: &str) -> Option<i32> {
+    string
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|token| !token.is_empty())
+        .try_fold(0, |mask, token| {
+            let interval = NOTE_NAMES
+                .iter()
+                .position(|name| name.eq_ignore_ascii_case(token))?;
+            Some(mask | 1 << interval)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn harmony(scale: Scale) -> Harmony {
+        Harmony {
+            scale,
+            ..Harmony::new()
+        }
+    }
+
+    #[test]
+    fn off_never_transposes() {
+        let mut rng = Rng::new(0);
+        let harmony = harmony(Scale::Off);
+        assert!((0..1000).all(|_| harmony.pick(&mut rng) == 0.0));
+    }
+
+    #[test]
+    fn zero_amount_never_transposes() {
+        let mut rng = Rng::new(0);
+        let harmony = Harmony {
+            amount: 0.0,
+            ..harmony(Scale::Major)
+        };
+        assert!((0..1000).all(|_| harmony.pick(&mut rng) == 0.0));
+    }
+
+    #[test]
+    fn picks_stay_in_the_scale_and_octave_range() {
+        let mut rng = Rng::new(1);
+        let harmony = Harmony {
+            octaves: 2,
+            ..harmony(Scale::Pentatonic)
+        };
+        for _ in 0..1000 {
+            let pick = harmony.pick(&mut rng) as u32;
+            assert!(pick <= 12 * 2 + 11);
+            assert!(Scale::Pentatonic.mask(0) & 1 << (pick % 12) != 0, "{pick}");
+        }
+    }
+
+    #[test]
+    fn every_octave_comes_up() {
+        let mut rng = Rng::new(2);
+        let harmony = Harmony {
+            octaves: 3,
+            ..harmony(Scale::Octaves)
+        };
+        let mut seen = [false; 4];
+        for _ in 0..1000 {
+            let pick = harmony.pick(&mut rng) as usize;
+            assert_eq!(pick % 12, 0);
+            seen[pick / 12] = true;
+        }
+        assert!(seen.iter().all(|seen| *seen));
+    }
+
+    #[test]
+    fn weighting_favours_consonant_intervals() {
+        let count = |weighting: f32| {
+            let mut rng = Rng::new(3);
+            let harmony = Harmony {
+                custom: 0b0000_1100_0000, // b5 and 5
+                octaves: 0,
+                weighting,
+                ..harmony(Scale::Custom)
+            };
+            let fifths = (0..10000).filter(|_| harmony.pick(&mut rng) == 7.0).count();
+            fifths as f32 / 10000.0
+        };
+
+        // equal weights split evenly, full weighting gives 0.8 against 0.1
+        assert!((count(0.0) - 0.5).abs() < 0.03);
+        assert!((count(1.0) - 0.8 / 0.9).abs() < 0.03);
+    }
+
+    #[test]
+    fn empty_custom_scale_stays_at_the_root() {
+        let mut rng = Rng::new(4);
+        let harmony = harmony(Scale::Custom);
+        assert!((0..100).all(|_| harmony.pick(&mut rng) == 0.0));
+    }
+
+    #[test]
+    fn mask_string_round_trip() {
+        let mask = Scale::Minor.mask(0) as i32;
+        assert_eq!(mask_to_string(mask), "1 2 b3 4 5 b6 b7");
+        assert_eq!(string_to_mask(&mask_to_string(mask)), Some(mask));
+        assert_eq!(mask_to_string(0), "-");
+    }
+
+    #[test]
+    fn string_to_mask_accepts_commas_and_any_case() {
+        assert_eq!(string_to_mask("1, B3,5"), Some(0b0000_1000_1001));
+        assert_eq!(string_to_mask(""), Some(0));
+        assert_eq!(string_to_mask("1 #4"), None);
+    }
+}
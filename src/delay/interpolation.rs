@@ -0,0 +1,244 @@
+use nih_plug::prelude::Enum;
+use std::f32::consts::PI;
+
+const SINC_HALF: usize = 8; // taps on each side of the read position
+const SINC_RESOLUTION: usize = 256; // table points per sample
+const SINC_ROLLOFF: f32 = 0.9; // keeps the transition band below nyquist
+
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Quality {
+    #[name = "Eco (Linear)"]
+    Eco,
+    #[name = "Normal (Cubic)"]
+    Normal,
+    #[name = "Good (6-Point)"]
+    Good,
+    #[name = "High (Sinc)"]
+    High,
+}
+
+// windowed sinc kernel, the cutoff is applied when reading so one table covers all pitches
+pub struct SincTable {
+    sinc: Vec<f32>,
+    window: Vec<f32>,
+}
+
+impl SincTable {
+    pub fn new() -> Self {
+        let size = SINC_HALF * SINC_RESOLUTION;
+        Self {
+            sinc: (0..=size)
+                .map(|i| {
+                    let x = PI * i as f32 / SINC_RESOLUTION as f32;
+                    if i == 0 {
+                        1.0
+                    } else {
+                        x.sin() / x
+                    }
+                })
+                .collect(),
+            // blackman over the half width
+            window: (0..=size)
+                .map(|i| {
+                    let x = PI * i as f32 / size as f32;
+                    0.42 + 0.5 * x.cos() + 0.08 * (2.0 * x).cos()
+                })
+                .collect(),
+        }
+    }
+
+    // distance in samples from the read position, cutoff relative to nyquist
+    fn weight(&self, distance: f32, cutoff: f32) -> f32 {
+        let distance = distance.abs();
+        cutoff
+            * lookup(&self.sinc, distance * cutoff * SINC_RESOLUTION as f32)
+            * lookup(&self.window, distance * SINC_RESOLUTION as f32)
+    }
+}
+
+fn lookup(table: &[f32], pos: f32) -> f32 {
+    let index = pos as usize;
+    if index + 1 >= table.len() {
+        return 0.0;
+    }
+    let t = pos - index as f32;
+    table[index] + (table[index + 1] - table[index]) * t
+}
+
+// N samples around base starting at base - before, only reads around the buffer edges wrap
+fn taps<const N: usize>(data: &[(f32, f32)], base: usize, before: usize) -> [(f32, f32); N] {
+    let len = data.len();
+    if base >= before && base + N - before <= len {
+        std::array::from_fn(|i| data[base - before + i])
+    } else {
+        std::array::from_fn(|i| data[(base + len * 2 - before + i) % len])
+    }
+}
+
+pub fn linear(data: &[(f32, f32)], pos: f32) -> (f32, f32) {
+    let base = pos as usize;
+    let t = pos - base as f32;
+    let [p0, p1] = taps::<2>(data, base, 0);
+    (p0.0 + (p1.0 - p0.0) * t, p0.1 + (p1.1 - p0.1) * t)
+}
+
+pub fn cubic(data: &[(f32, f32)], pos: f32) -> (f32, f32) {
+    let base = pos as usize;
+    let t = pos - base as f32;
+    let [p0, p1, p2, p3] = taps::<4>(data, base, 1);
+
+    let left = p1.0
+        + 0.5
+            * t
+            * (p2.0 - p0.0
+                + t * (2.0 * p0.0 - 5.0 * p1.0 + 4.0 * p2.0 - p3.0
+                    + t * (3.0 * (p1.0 - p2.0) + p3.0 - p0.0)));
+    let right = p1.1
+        + 0.5
+            * t
+            * (p2.1 - p0.1
+                + t * (2.0 * p0.1 - 5.0 * p1.1 + 4.0 * p2.1 - p3.1
+                    + t * (3.0 * (p1.1 - p2.1) + p3.1 - p0.1)));
+    (left, right)
+}
+
+// 5th order lagrange through the points at -2 to 3
+pub fn lagrange(data: &[(f32, f32)], pos: f32) -> (f32, f32) {
+    let base = pos as usize;
+    let t = pos - base as f32;
+    let points = taps::<6>(data, base, 2);
+
+    let (a, b, c, d, e, f) = (t + 2.0, t + 1.0, t, t - 1.0, t - 2.0, t - 3.0);
+    let weights = [
+        -b * c * d * e * f / 120.0,
+        a * c * d * e * f / 24.0,
+        -a * b * d * e * f / 12.0,
+        a * b * c * e * f / 12.0,
+        -a * b * c * d * f / 24.0,
+        a * b * c * d * e / 120.0,
+    ];
+
+    points
+        .iter()
+        .zip(weights)
+        .fold((0.0, 0.0), |sum, (point, weight)| {
+            (sum.0 + point.0 * weight, sum.1 + point.1 * weight)
+        })
+}
+
+// ratio is the playback speed, above 1 the cutoff moves down to stop the grain from aliasing
+pub fn sinc(data: &[(f32, f32)], pos: f32, ratio: f32, table: &SincTable) -> (f32, f32) {
+    let base = pos as usize;
+    let t = pos - base as f32;
+    let points = taps::<{ SINC_HALF * 2 }>(data, base, SINC_HALF - 1);
+    let cutoff = SINC_ROLLOFF / ratio.abs().max(1.0);
+
+    let mut sum = (0.0, 0.0);
+    let mut weight_sum = 0.0;
+    for (i, point) in points.iter().enumerate() {
+        let weight = table.weight(i as f32 - (SINC_HALF - 1) as f32 - t, cutoff);
+        sum.0 += point.0 * weight;
+        sum.1 += point.1 * weight;
+        weight_sum += weight;
+    }
+
+    // the truncated kernel does not add up to exactly 1
+    (sum.0 / weight_sum, sum.1 / weight_sum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::TAU;
+
+    // left channel is a ramp, right channel its negative
+    fn ramp(len: usize) -> Vec<(f32, f32)> {
+        (0..len).map(|i| (i as f32, -(i as f32))).collect()
+    }
+
+    fn sine(len: usize, period: f32) -> Vec<(f32, f32)> {
+        (0..len)
+            .map(|i| {
+                let value = (TAU * i as f32 / period).sin();
+                (value, value)
+            })
+            .collect()
+    }
+
+    type Kernel = fn(&[(f32, f32)], f32) -> (f32, f32);
+
+    fn polynomial_kernels() -> [Kernel; 3] {
+        [linear, cubic, lagrange]
+    }
+
+    #[test]
+    fn polynomial_kernels_hit_the_samples() {
+        let data = sine(64, 7.3);
+        for kernel in polynomial_kernels() {
+            for (i, sample) in data.iter().enumerate() {
+                assert_eq!(kernel(&data, i as f32), *sample, "{i}");
+            }
+        }
+    }
+
+    #[test]
+    fn polynomial_kernels_follow_a_ramp() {
+        let data = ramp(64);
+        for kernel in polynomial_kernels() {
+            for pos in [10.25, 20.5, 30.75] {
+                let (left, right) = kernel(&data, pos);
+                assert!((left - pos).abs() < 1e-4, "{pos}");
+                assert!((right + pos).abs() < 1e-4, "{pos}");
+            }
+        }
+    }
+
+    #[test]
+    fn reads_wrap_around_the_buffer_edge() {
+        let mut data = vec![(0.0, 0.0); 32];
+        data[0] = (1.0, 1.0);
+        // halfway between the last and the first sample
+        assert_eq!(linear(&data, 31.5), (0.5, 0.5));
+        assert_eq!(cubic(&data, 31.0), (0.0, 0.0));
+        assert!(lagrange(&data, 31.5).0 > 0.0);
+    }
+
+    #[test]
+    fn sinc_keeps_dc() {
+        let table = SincTable::new();
+        let data = vec![(0.5, -0.5); 64];
+        for pos in [20.0, 20.3, 20.9] {
+            let (left, right) = sinc(&data, pos, 1.0, &table);
+            assert!((left - 0.5).abs() < 1e-5);
+            assert!((right + 0.5).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn sinc_follows_a_slow_sine() {
+        let table = SincTable::new();
+        let period = 32.0;
+        let data = sine(256, period);
+        for pos in [100.0, 100.25, 100.5, 100.75] {
+            let expected = (TAU * pos / period).sin();
+            assert!(
+                (sinc(&data, pos, 1.0, &table).0 - expected).abs() < 0.01,
+                "{pos}"
+            );
+        }
+    }
+
+    #[test]
+    fn sinc_filters_above_the_lowered_cutoff() {
+        let table = SincTable::new();
+        // close to nyquist, a grain played at twice the speed would alias it
+        let data = sine(256, 2.2);
+        let peak = |ratio: f32| {
+            (0..40)
+                .map(|i| sinc(&data, 100.0 + i as f32 * 0.1, ratio, &table).0.abs())
+                .fold(0.0, f32::max)
+        };
+        assert!(peak(1.0) > 0.5);
+        assert!(peak(2.0) < 0.1);
+    }
+}
@@ -0,0 +1,227 @@
+use super::rng::Rng;
+use nih_plug::prelude::Enum;
+use std::f32::consts::{PI, TAU};
+
+pub const LFO_NUM: usize = 4;
+
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LfoShape {
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+    // a new random value every cycle
+    #[name = "Sample & Hold"]
+    SampleHold,
+    // glides from one random value to the next over a cycle
+    #[name = "Smooth Random"]
+    SmoothRandom,
+}
+
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LfoTarget {
+    Off,
+    Distance,
+    #[name = "Window Size"]
+    WindowSize,
+    #[name = "Grain Size"]
+    GrainSize,
+    Density,
+    Pitch,
+    Gain,
+    // feedback and color are global, the playhead of the lfo is ignored for them
+    Feedback,
+    Color,
+}
+
+// summed lfo values of one playhead, every field is in units of full depth range -1 to 1
+#[derive(Default, Clone, Copy)]
+pub struct Modulation {
+    pub distance: f32,
+    pub window_size: f32,
+    pub grain_size: f32,
+    pub density: f32,
+    pub pitch: f32,
+    pub gain: f32,
+}
+
+pub struct Lfo {
+    pub shape: LfoShape,
+    pub target: LfoTarget,
+    pub play_head: usize,
+    pub depth: f32, // range -1 to 1
+    pub value: f32, // last output before depth range -1 to 1
+    inc: f32,
+    phase: f32,
+    sample_rate: f32,
+    rng: Rng,
+    held: f32,     // random value of the current cycle
+    previous: f32, // random value of the last cycle, smooth random starts from it
+}
+
+impl Lfo {
+    pub fn new() -> Self {
+        Self {
+            shape: LfoShape::Sine,
+            target: LfoTarget::Off,
+            play_head: 0,
+            depth: 0.0,
+            value: 0.0,
+            inc: 0.0,
+            phase: 0.0,
+            sample_rate: 0.0,
+            rng: Rng::new(0),
+            held: 0.0,
+            previous: 0.0,
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+    }
+
+    // restarts the cycle, so the random shapes repeat as well
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng.seed(seed);
+        self.phase = 0.0;
+        self.previous = 0.0;
+        self.held = self.rng.next_bipolar();
+    }
+
+    pub fn set_rate(&mut self, freq: f32) {
+        self.inc = freq / self.sample_rate;
+    }
+
+    // beat_pos is the song position in beats, None if the transport is stopped
+    pub fn set_rate_synced(&mut self, beats: f32, tempo: f32, beat_pos: Option<f64>) {
+        self.set_rate(tempo / 60.0 / beats);
+        if let Some(beat_pos) = beat_pos {
+            self.phase = (beat_pos / beats as f64).rem_euclid(1.0) as f32;
+        }
+    }
+
+    pub fn next(&mut self) -> f32 {
+        let phase = self.phase;
+        self.value = match self.shape {
+            LfoShape::Sine => (TAU * phase).sin(),
+            // starts at 0 and rises like the sine
+            LfoShape::Triangle => 1.0 - 4.0 * ((phase + 0.25).fract() - 0.5).abs(),
+            LfoShape::Saw => 2.0 * phase - 1.0,
+            LfoShape::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            LfoShape::SampleHold => self.held,
+            LfoShape::SmoothRandom => {
+                let t = 0.5 - 0.5 * (PI * phase).cos();
+                self.previous + (self.held - self.previous) * t
+            }
+        };
+
+        self.phase += self.inc;
+        if self.phase >= 1.0 {
+            self.phase = self.phase.fract();
+            self.previous = self.held;
+            self.held = self.rng.next_bipolar();
+        }
+
+        self.value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // four samples per cycle
+    fn lfo(shape: LfoShape) -> Lfo {
+        let mut lfo = Lfo::new();
+        lfo.shape = shape;
+        lfo.set_sample_rate(4.0);
+        lfo.set_rate(1.0);
+        lfo
+    }
+
+    fn cycle(lfo: &mut Lfo) -> Vec<f32> {
+        (0..4).map(|_| lfo.next()).collect()
+    }
+
+    fn assert_close(values: &[f32], expected: &[f32]) {
+        for (value, expected) in values.iter().zip(expected) {
+            assert!((value - expected).abs() < 1e-5, "{values:?} {expected:?}");
+        }
+    }
+
+    #[test]
+    fn periodic_shapes() {
+        let expected = [
+            (LfoShape::Sine, [0.0, 1.0, 0.0, -1.0]),
+            (LfoShape::Triangle, [0.0, 1.0, 0.0, -1.0]),
+            (LfoShape::Saw, [-1.0, -0.5, 0.0, 0.5]),
+            (LfoShape::Square, [1.0, 1.0, -1.0, -1.0]),
+        ];
+        for (shape, expected) in expected {
+            let mut lfo = lfo(shape);
+            assert_close(&cycle(&mut lfo), &expected);
+            // the second cycle repeats the first
+            assert_close(&cycle(&mut lfo), &expected);
+        }
+    }
+
+    #[test]
+    fn sample_hold_changes_once_per_cycle() {
+        let mut lfo = lfo(LfoShape::SampleHold);
+        lfo.set_seed(1);
+        let first = cycle(&mut lfo);
+        let second = cycle(&mut lfo);
+        assert!(first.iter().all(|value| *value == first[0]));
+        assert!(second.iter().all(|value| *value == second[0]));
+        assert_ne!(first[0], second[0]);
+        assert!((-1.0..1.0).contains(&first[0]));
+    }
+
+    #[test]
+    fn smooth_random_glides_between_the_held_values() {
+        let mut lfo = lfo(LfoShape::SmoothRandom);
+        lfo.set_seed(2);
+        let held = lfo.held;
+        // starts from 0 after seeding, moves towards the held value and reaches it as the next
+        // cycle begins
+        let values = cycle(&mut lfo);
+        assert_eq!(values[0], 0.0);
+        assert!(values
+            .windows(2)
+            .all(|pair| (pair[1] - pair[0]) * held >= 0.0));
+        assert_eq!(lfo.next(), held);
+    }
+
+    #[test]
+    fn seed_repeats_the_random_shapes() {
+        let mut a = lfo(LfoShape::SampleHold);
+        let mut b = lfo(LfoShape::SampleHold);
+        a.set_seed(3);
+        b.set_seed(3);
+        let values = |lfo: &mut Lfo| (0..32).map(|_| lfo.next()).collect::<Vec<_>>();
+        let first = values(&mut a);
+        assert_eq!(first, values(&mut b));
+        a.set_seed(3);
+        assert_eq!(first, values(&mut a));
+    }
+
+    #[test]
+    fn synced_phase_follows_the_song_position() {
+        let mut lfo = lfo(LfoShape::Saw);
+        // one cycle per two beats, half way through the cycle at beat 5
+        lfo.set_rate_synced(2.0, 120.0, Some(5.0));
+        assert_close(&[lfo.next()], &[0.0]);
+        assert!((lfo.inc - 0.25).abs() < 1e-6);
+
+        // a stopped transport keeps the running phase
+        let phase = lfo.phase;
+        lfo.set_rate_synced(2.0, 120.0, None);
+        assert_eq!(lfo.phase, phase);
+    }
+}
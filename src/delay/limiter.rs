@@ -0,0 +1,37 @@
+const THRESHOLD: f32 = 0.9;
+const RELEASE_SEC: f32 = 0.1;
+
+// stereo linked peak limiter with instant attack, nothing below the threshold is touched
+pub struct Limiter {
+    envelope: f32,
+    release: f32,
+}
+
+impl Limiter {
+    pub fn new() -> Self {
+        Self {
+            envelope: 0.0,
+            release: 0.0,
+        }
+    }
+
+    pub fn update_sample_rate(&mut self, sample_rate: f32) {
+        self.release = (-1.0 / (RELEASE_SEC * sample_rate)).exp();
+    }
+
+    pub fn reset(&mut self) {
+        self.envelope = 0.0;
+    }
+
+    pub fn process(&mut self, input: (f32, f32)) -> (f32, f32) {
+        let peak = input.0.abs().max(input.1.abs());
+        self.envelope = peak.max(self.envelope * self.release);
+
+        if self.envelope > THRESHOLD {
+            let gain = THRESHOLD / self.envelope;
+            (input.0 * gain, input.1 * gain)
+        } else {
+            input
+        }
+    }
+}
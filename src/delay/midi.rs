@@ -0,0 +1,98 @@
+use nih_plug::prelude::Enum;
+
+const NOTE_NUM: usize = 128;
+const CHANNEL_NUM: usize = 16;
+
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MidiMode {
+    Off,
+    // every note spawns a burst of grains, the trigger keeps running on its own
+    #[name = "Bursts"]
+    Burst,
+    // like Burst, but the trigger only spawns grains while a note is held
+    #[name = "Bursts + Gate"]
+    Gate,
+}
+
+// notes in the order they were pressed, the last one sets the pitch of the playhead. the same note
+// held on two channels counts twice
+pub struct HeldNotes {
+    notes: Vec<(u8, u8, f32)>, // channel, note number and velocity
+}
+
+impl HeldNotes {
+    pub fn new() -> Self {
+        Self {
+            // every note can only be held once per channel, so pushing never allocates
+            notes: Vec::with_capacity(CHANNEL_NUM * NOTE_NUM),
+        }
+    }
+
+    pub fn press(&mut self, channel: u8, note: u8, velocity: f32) {
+        self.release(channel, note);
+        self.notes.push((channel, note, velocity));
+    }
+
+    pub fn release(&mut self, channel: u8, note: u8) {
+        self.notes
+            .retain(|(held_channel, held, _)| (*held_channel, *held) != (channel, note));
+    }
+
+    // note number and velocity
+    pub fn last(&self) -> Option<(u8, f32)> {
+        self.notes
+            .last()
+            .map(|(_, note, velocity)| (*note, *velocity))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn last_pressed_note_wins() {
+        let mut notes = HeldNotes::new();
+        assert_eq!(notes.last(), None);
+        notes.press(0, 60, 0.5);
+        notes.press(0, 64, 0.7);
+        assert_eq!(notes.last(), Some((64, 0.7)));
+    }
+
+    #[test]
+    fn releasing_falls_back_to_the_previous_note() {
+        let mut notes = HeldNotes::new();
+        notes.press(0, 60, 0.5);
+        notes.press(0, 64, 0.7);
+        notes.press(0, 67, 0.9);
+        notes.release(0, 67);
+        assert_eq!(notes.last(), Some((64, 0.7)));
+        notes.release(0, 60);
+        assert_eq!(notes.last(), Some((64, 0.7)));
+        notes.release(0, 64);
+        assert_eq!(notes.last(), None);
+    }
+
+    #[test]
+    fn pressing_again_moves_the_note_to_the_top() {
+        let mut notes = HeldNotes::new();
+        notes.press(0, 60, 0.5);
+        notes.press(0, 64, 0.7);
+        notes.press(0, 60, 0.3);
+        assert_eq!(notes.last(), Some((60, 0.3)));
+        notes.release(0, 60);
+        assert_eq!(notes.last(), Some((64, 0.7)));
+    }
+
+    #[test]
+    fn channels_are_held_separately() {
+        let mut notes = HeldNotes::new();
+        notes.press(0, 60, 0.5);
+        notes.press(1, 60, 0.7);
+        notes.release(1, 60);
+        assert_eq!(notes.last(), Some((60, 0.5)));
+        // a release on a channel that never pressed the note changes nothing
+        notes.release(2, 60);
+        assert_eq!(notes.last(), Some((60, 0.5)));
+    }
+}
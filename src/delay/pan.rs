@@ -0,0 +1,48 @@
+use nih_plug::prelude::Enum;
+use std::f32::consts::FRAC_PI_4;
+
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PanLaw {
+    Linear,
+    #[name = "Constant Power"]
+    ConstantPower,
+}
+
+impl PanLaw {
+    // stereo_pos range -1 to 1, returns the gains of the left and right channel
+    pub fn gains(self, stereo_pos: f32) -> (f32, f32) {
+        match self {
+            PanLaw::Linear => (0.5 * (1.0 - stereo_pos), 0.5 * (1.0 + stereo_pos)),
+            PanLaw::ConstantPower => {
+                let angle = (stereo_pos + 1.0) * FRAC_PI_4;
+                (angle.cos(), angle.sin())
+            }
+        }
+    }
+}
+
+// how the two channels of the buffer are used. in mid/side mid and side are granulated
+// separately and every grain is decoded to left and right before it is panned
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum StereoMode {
+    #[name = "Left/Right"]
+    LeftRight,
+    #[name = "Mid/Side"]
+    MidSide,
+}
+
+impl StereoMode {
+    pub fn encode(self, (left, right): (f32, f32)) -> (f32, f32) {
+        match self {
+            StereoMode::LeftRight => (left, right),
+            StereoMode::MidSide => ((left + right) * 0.5, (left - right) * 0.5),
+        }
+    }
+
+    pub fn decode(self, (mid, side): (f32, f32)) -> (f32, f32) {
+        match self {
+            StereoMode::LeftRight => (mid, side),
+            StereoMode::MidSide => (mid + side, mid - side),
+        }
+    }
+}
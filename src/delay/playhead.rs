@@ -1,33 +1,94 @@
-use super::envelope::Envelope;
+use super::harmony::Harmony;
+use super::lfo::Modulation;
+use super::rng::Rng;
+use super::trig::{Trig, TriggerMode};
+use super::window::{Window, WindowShape, WindowTables};
+use nih_plug::prelude::Enum;
+
+pub const RELEASE_SLOTS: usize = 16; // extra grains that let stolen grains fade out
+const STEAL_FADE_SEC: f32 = 0.005;
+const MOD_DENSITY_OCTAVES: f32 = 3.0; // density change of a full depth lfo up and down
+const MOD_PITCH_SEMITONES: f32 = 12.0;
 
 fn lerp(v0: f32, v1: f32, t: f32) -> f32 {
     (1.0 - t) * v0 + t * v1
 }
 
-#[derive(PartialEq)]
+fn semitones_to_ratio(semitones: f32) -> f32 {
+    (2.0f32).powf(semitones / 12.0)
+}
+
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
 pub enum FeedbackSrc {
     Playhead,
+    #[name = "Grains"]
     Grain,
 }
 
+// the buffer the grains of a playhead are read from
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Source {
+    Main,
+    Sidechain,
+}
+
+// the buffer channels a grain reads
+#[derive(Default, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Channels {
+    #[default]
+    Both,
+    Mid,
+    Side,
+}
+
+// what happens to a new grain once all grains of the pool are playing
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Stealing {
+    #[name = "Drop New"]
+    DropNew,
+    #[name = "Steal Oldest"]
+    Oldest,
+    #[name = "Steal Quietest"]
+    Quietest,
+}
+
 pub struct PlayHead {
+    pub enabled: bool,
     sample_rate: f32,
     buffer_length_sec: f32,
     pub distance: f32,         // distance from record_head range 0-1
     pub current_distance: f32, // current distance interpolates to distance
     pub window_size: f32,      // window_size range between 0-1
     grain_size: f32,
-    trig: Trig, // triggers grains
+    trig: Trig,      // triggers grains
+    side_trig: Trig, // triggers the side grains while split
+    split: bool,     // mid/side: mid and side are granulated separately, each with its own trigger
     pub grains: Vec<Grain>,
-    grain_num: usize,
+    grain_num: usize, // pool size, grains fading out after being stolen are not counted
+    stealing: Stealing,
     pub feedback_src: FeedbackSrc,
-    pitch: i32,
+    pub source: Source,
+    pitch: f32,        // pitch in semitones
+    pitch_ratio: f32,  // playback speed derived from pitch
+    pitch_spread: f32, // max random detune of a grain in semitones
+    spread: f32,       // stereo width of the grains range 0-1
+    pan: f32,          // stereo center of the grains range -1 to 1
+    harmony: Harmony,
     gain: f32,
+    window: Window,
+    reverse: f32, // probability of a grain playing reversed range 0-1
+    rng: Rng,
+    note: Option<(f32, f32)>, // transposition in semitones and gain of the held midi note
+    gated: bool,              // the trigger only spawns grains while a note is held
+    modulation: Modulation,
+    mod_pitch_ratio: f32,   // playback speed on top of pitch_ratio from the lfos
+    mod_density_ratio: f32, // trigger speed from the lfos
 }
 
 impl PlayHead {
     pub fn new(distance: f32, grain_num: usize) -> Self {
         PlayHead {
+            enabled: true,
             sample_rate: 0.0,
             buffer_length_sec: 0.0,
             distance,
@@ -35,37 +96,117 @@ impl PlayHead {
             window_size: 0.0,
             grain_size: 0.0,
             trig: Trig::new(),
+            side_trig: Trig::new(),
+            split: false,
             grain_num,
+            stealing: Stealing::DropNew,
             grains: {
-                let mut grains: Vec<Grain> = Vec::with_capacity(grain_num);
-                for _ in 0..grain_num {
+                let mut grains: Vec<Grain> = Vec::with_capacity(grain_num + RELEASE_SLOTS);
+                for _ in 0..grain_num + RELEASE_SLOTS {
                     grains.push(Grain::default());
                 }
                 grains
             },
             feedback_src: FeedbackSrc::Playhead,
-            pitch: 0,
+            source: Source::Main,
+            pitch: 0.0,
+            pitch_ratio: 1.0,
+            pitch_spread: 0.0,
+            spread: 1.0,
+            pan: 0.0,
+            harmony: Harmony::new(),
             gain: 0.0,
+            window: Window::default(),
+            reverse: 0.0,
+            rng: Rng::new(0),
+            note: None,
+            gated: false,
+            modulation: Modulation::default(),
+            mod_pitch_ratio: 1.0,
+            mod_density_ratio: 1.0,
         }
     }
 
-    #[allow(dead_code)]
+    // restarts the random sequence, the trigger starts over so the grain timing repeats as well
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng.seed(seed);
+        self.trig.reset();
+        self.side_trig.reset();
+    }
+
+    pub fn set_split(&mut self, split: bool) {
+        self.split = split;
+    }
+
+    // stops every grain and jumps to the target distance
+    pub fn reset(&mut self) {
+        self.grains.iter_mut().for_each(|grain| {
+            grain.active = false;
+            grain.releasing = false;
+        });
+        self.current_distance = self.distance;
+    }
+
+    // grains above a lowered pool size play until they end
+    pub fn set_grain_num(&mut self, grain_num: usize) {
+        self.grain_num = grain_num.clamp(1, self.grains.len() - RELEASE_SLOTS);
+    }
+
+    pub fn set_stealing(&mut self, stealing: Stealing) {
+        self.stealing = stealing;
+    }
+
+    pub fn set_source(&mut self, source: Source) {
+        self.source = source;
+    }
+
     pub fn set_feedback_src(&mut self, feedback_src: FeedbackSrc) {
         self.feedback_src = feedback_src;
     }
 
-    pub fn set_pitch(&mut self, pitch: i32) {
-        self.pitch = pitch;
+    pub fn set_pitch(&mut self, pitch: f32) {
+        if pitch != self.pitch {
+            self.pitch = pitch;
+            self.pitch_ratio = semitones_to_ratio(pitch);
+        }
+    }
+
+    pub fn set_pitch_spread(&mut self, pitch_spread: f32) {
+        self.pitch_spread = pitch_spread;
+    }
+
+    pub fn set_spread(&mut self, spread: f32) {
+        self.spread = spread;
+    }
+
+    pub fn set_pan(&mut self, pan: f32) {
+        self.pan = pan;
+    }
+
+    pub fn set_harmony(&mut self, harmony: Harmony) {
+        self.harmony = harmony;
     }
 
     pub fn set_gain(&mut self, gain: f32) {
         self.gain = gain;
     }
 
+    pub fn set_reverse(&mut self, reverse: f32) {
+        self.reverse = reverse;
+    }
+
     pub fn set_window_size(&mut self, window_size: f32) {
         self.window_size = window_size;
     }
 
+    pub fn set_window_shape(&mut self, shape: WindowShape) {
+        self.window.shape = shape;
+    }
+
+    pub fn set_window_flat(&mut self, flat: f32) {
+        self.window.flat = flat;
+    }
+
     pub fn set_grain_size(&mut self, grain_size: f32) {
         self.grain_size = grain_size;
     }
@@ -74,10 +215,34 @@ impl PlayHead {
         self.distance = distance;
     }
 
+    pub fn set_distance_synced(&mut self, beats: f32, tempo: f32) {
+        let distance_sec = beats * 60.0 / tempo;
+        self.distance = (distance_sec / self.buffer_length_sec).min(1.0);
+    }
+
     pub fn init(&mut self, sample_rate: f32, buffer_length_sec: f32) {
         self.sample_rate = sample_rate;
         self.buffer_length_sec = buffer_length_sec;
         self.trig.set_sample_rate(sample_rate);
+        self.side_trig.set_sample_rate(sample_rate);
+        for grain in self.grains.iter_mut() {
+            grain.init(sample_rate, buffer_length_sec);
+        }
+    }
+
+    pub fn set_modulation(&mut self, modulation: Modulation) {
+        // the ratios cost a powf each, most of the time nothing is modulated
+        if modulation.pitch != self.modulation.pitch {
+            self.mod_pitch_ratio = semitones_to_ratio(modulation.pitch * MOD_PITCH_SEMITONES);
+        }
+        if modulation.density != self.modulation.density {
+            self.mod_density_ratio = (2.0f32).powf(modulation.density * MOD_DENSITY_OCTAVES);
+        }
+        self.modulation = modulation;
+    }
+
+    pub fn modulated_distance(&self) -> f32 {
+        (self.current_distance + self.modulation.distance).clamp(0.0, 1.0)
     }
 
     pub fn set_current_distance(&mut self) {
@@ -87,38 +252,98 @@ impl PlayHead {
     }
 
     pub fn set_density(&mut self, density: f32) {
-        self.trig.set_inc(density);
+        for trig in [&mut self.trig, &mut self.side_trig] {
+            trig.synced = false;
+            trig.set_inc(density);
+        }
     }
 
-    pub fn get_grain_data(&self) -> Vec<(f32, f32, f32)> {
-        let mut data = Vec::new();
-        for grain in self.grains.iter() {
-            if grain.active {
-                data.push((grain.pos, grain.gain, grain.stereo_pos));
+    // beat_pos is the song position in beats, None if the transport is stopped. aligning to it
+    // instead of the bar keeps divisions longer than a bar on their own grid
+    pub fn set_density_synced(&mut self, beats: f32, tempo: f32, beat_pos: Option<f64>) {
+        // a modulated rate has no grid to align to
+        let phase = beat_pos
+            .filter(|_| self.modulation.density == 0.0)
+            .map(|beat_pos| (beat_pos / beats as f64).rem_euclid(1.0) as f32);
+        for trig in [&mut self.trig, &mut self.side_trig] {
+            trig.synced = true;
+            trig.set_inc(tempo / 60.0 / beats);
+            if let Some(phase) = phase {
+                trig.align(phase);
             }
         }
-        data
+    }
+
+    pub fn has_active_grains(&self) -> bool {
+        self.grains.iter().any(|grain| grain.active)
     }
 
     pub fn set_chaos(&mut self, chaos: f32) {
         self.trig.chaos = chaos;
+        self.side_trig.chaos = chaos;
+    }
+
+    pub fn set_trigger_mode(&mut self, mode: TriggerMode) {
+        self.trig.mode = mode;
+        self.side_trig.mode = mode;
+    }
+
+    pub fn set_note(&mut self, note: Option<(f32, f32)>) {
+        self.note = note;
+    }
+
+    pub fn set_gated(&mut self, gated: bool) {
+        self.gated = gated;
+    }
+
+    // grains spawned at once by a note, they take part in voice stealing like any other grain
+    pub fn burst(&mut self, grain_num: usize) {
+        if !self.enabled {
+            return;
+        }
+        for _ in 0..grain_num {
+            if self.split {
+                self.activate_grain(Channels::Mid);
+                self.activate_grain(Channels::Side);
+            } else {
+                self.activate_grain(Channels::Both);
+            }
+        }
     }
 
-    pub fn update(&mut self) {
+    pub fn update(&mut self, windows: &WindowTables) {
         self.set_current_distance();
-        if self.trig.update() {
-            self.activate_grain();
+        let open = !self.gated || self.note.is_some();
+        let play = self.enabled && open;
+        if self.trig.update(&mut self.rng, self.mod_density_ratio) && play {
+            let channels = if self.split {
+                Channels::Mid
+            } else {
+                Channels::Both
+            };
+            self.activate_grain(channels);
         }
+        if self.split && self.side_trig.update(&mut self.rng, self.mod_density_ratio) && play {
+            self.activate_grain(Channels::Side);
+        }
+        let pitch_ratio = self.pitch_ratio * self.mod_pitch_ratio;
+        let gain = (self.gain + self.modulation.gain).clamp(0.0, 1.0);
         for grain in self.grains.iter_mut() {
             if grain.active {
-                grain.update(self.pitch, self.gain);
+                grain.update(pitch_ratio, gain, windows);
             }
         }
     }
 
+    fn playing_grain_num(&self) -> usize {
+        self.grains
+            .iter()
+            .filter(|grain| grain.active && !grain.releasing)
+            .count()
+    }
+
     pub fn get_init_gain(&self) -> f32 {
-        let active_grain_num = self.grains.iter().filter(|grain| grain.active).count();
-        let ratio = active_grain_num as f32 / self.grain_num as f32;
+        let ratio = (self.playing_grain_num() as f32 / self.grain_num as f32).min(1.0);
 
         let decay_factor = 0.7;
         let scaled_ratio = (1.0 - ratio).powf(decay_factor);
@@ -126,20 +351,61 @@ impl PlayHead {
         scaled_ratio.max(0.3)
     }
 
-    fn activate_grain(&mut self) {
-        let init_gain = self.get_init_gain();
+    // makes room for a new grain, false if the grain has to be dropped
+    fn steal_grain(&mut self) -> bool {
+        let playing = self
+            .grains
+            .iter_mut()
+            .filter(|grain| grain.active && !grain.releasing);
+        let victim = match self.stealing {
+            Stealing::DropNew => None,
+            Stealing::Oldest => playing.max_by_key(|grain| grain.counter),
+            Stealing::Quietest => playing.min_by(|a, b| a.loudness().total_cmp(&b.loudness())),
+        };
+
+        match victim {
+            Some(grain) => {
+                grain.release(1.0 / (STEAL_FADE_SEC * self.sample_rate));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn activate_grain(&mut self, channels: Channels) {
+        // without a free grain all release slots are still fading out and the grain is dropped,
+        // before a playing grain is stolen for nothing
+        if self.grains.iter().all(|grain| grain.active) {
+            return;
+        }
+        if self.playing_grain_num() >= self.grain_num && !self.steal_grain() {
+            return;
+        }
+
+        let (transpose, velocity) = self.note.unwrap_or((0.0, 1.0));
+        let init_gain = self.get_init_gain() * velocity;
+        let window_size = (self.window_size + self.modulation.window_size).clamp(0.0, 1.0);
+        let grain_size = (self.grain_size + self.modulation.grain_size).clamp(0.0, 1.0);
+        let distance = self.modulated_distance();
         for grain in self.grains.iter_mut() {
             if !grain.active {
-                let pos = rand::random::<f32>() * 2.0 - 1.0;
+                let pos = window_size * 0.25 * self.rng.next_bipolar() + distance;
+                let stereo_pos =
+                    (self.pan + self.spread * self.rng.next_bipolar()).clamp(-1.0, 1.0);
+                let reverse = self.rng.next_f32() < self.reverse;
+                let detune = self.rng.next_bipolar() * self.pitch_spread
+                    + self.harmony.pick(&mut self.rng)
+                    + transpose;
                 grain.activate(
                     pos,
-                    (self.grain_size * self.sample_rate) as usize, // max 1sec
+                    (grain_size * self.sample_rate) as usize, // max 1sec
                     init_gain,
-                    self.window_size,
-                    self.distance,
-                    self.sample_rate,
-                    self.buffer_length_sec,
+                    self.window,
+                    stereo_pos,
+                    reverse,
+                    semitones_to_ratio(detune),
                 );
+                grain.channels = channels;
                 break;
             }
         }
@@ -158,21 +424,35 @@ pub struct Grain {
     counter: usize,
     pub gain: f32,
     init_gain: f32,
-    env: Envelope,
+    level: f32,     // init_gain times the window, the gain without the playhead gain
+    decaying: bool, // the window passed its peak
+    window: Window,
+    reverse: bool,
+    detune: f32,    // speed ratio on top of the playhead pitch
+    pub ratio: f32, // playback speed through the buffer, 1 is the original pitch
+    pub channels: Channels,
+    releasing: bool, // stolen and fading out
+    release_gain: f32,
+    release_step: f32,
 }
 
 impl Grain {
+    fn init(&mut self, sample_rate: f32, buffer_length_sec: f32) {
+        self.sample_rate = sample_rate;
+        self.buffer_length_sec = buffer_length_sec;
+    }
+
     fn activate(
         &mut self,
         pos: f32,
         length: usize,
         init_gain: f32,
-        window_size: f32,
-        playhead_distance: f32,
-        sample_rate: f32,
-        buffer_length_sec: f32,
+        window: Window,
+        stereo_pos: f32,
+        reverse: bool,
+        detune: f32,
     ) {
-        self.pos = window_size * 0.25 * pos + playhead_distance;
+        self.pos = pos;
 
         if self.pos < 0.0 {
             self.pos = 1.0 + self.pos;
@@ -181,17 +461,44 @@ impl Grain {
         }
 
         self.active = true;
+        self.counter = 0;
         self.length = length;
-        self.env.set_inc(1.0 / length as f64);
-        self.stereo_pos = rand::random::<f32>() * 2.0 - 1.0;
+        self.window = window;
+        self.stereo_pos = stereo_pos;
         self.init_gain = init_gain;
-        self.sample_rate = sample_rate;
-        self.buffer_length_sec = buffer_length_sec;
+        self.reverse = reverse;
+        self.detune = detune;
+        self.releasing = false;
+        self.release_gain = 1.0;
+        self.level = 0.0;
+        self.decaying = false;
+    }
+
+    // a grain in its attack is rated by the level it is heading for, so it is not taken for a
+    // quiet one
+    fn loudness(&self) -> f32 {
+        if self.decaying {
+            self.level
+        } else {
+            self.init_gain
+        }
+    }
+
+    fn release(&mut self, step: f32) {
+        self.releasing = true;
+        self.release_step = step;
     }
 
-    fn update(&mut self, pitch: i32, gain: f32) {
-        let pitch = (2.0f32).powf(pitch as f32 / 12.0);
-        let inc = (1.0 - pitch) / (self.sample_rate * self.buffer_length_sec);
+    fn update(&mut self, pitch_ratio: f32, gain: f32, windows: &WindowTables) {
+        let pitch = pitch_ratio * self.detune;
+        self.ratio = pitch;
+        // the write head moves away by one sample, so a reversed grain moves away faster
+        let speed = if self.reverse {
+            1.0 + pitch
+        } else {
+            1.0 - pitch
+        };
+        let inc = speed / (self.sample_rate * self.buffer_length_sec);
 
         self.pos += inc;
 
@@ -202,48 +509,116 @@ impl Grain {
         }
 
         self.counter += 1;
-        self.gain = self.env.next_sample() as f32 * self.init_gain * gain;
-        if self.counter > self.length {
+        let phase = self.counter as f32 / self.length as f32;
+        if self.releasing {
+            self.release_gain = (self.release_gain - self.release_step).max(0.0);
+        }
+        let level = windows.get(self.window, phase) * self.init_gain;
+        self.decaying |= level < self.level;
+        self.level = level;
+        self.gain = level * gain * self.release_gain;
+        if self.counter > self.length || self.release_gain <= 0.0 {
             self.active = false;
             self.counter = 0;
         }
     }
 }
 
-struct Trig {
-    inc: f32,
-    phase: f32,
-    sample_rate: f32,
-    chaos: f32,
-    rnd: f32,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // grains of 100 samples, a stolen grain fades out within 5 samples
+    fn play_head(grain_num: usize, stealing: Stealing) -> PlayHead {
+        let mut play_head = PlayHead::new(0.5, grain_num);
+        play_head.init(1000.0, 1.0);
+        play_head.set_grain_size(0.1);
+        play_head.set_gain(1.0);
+        play_head.set_stealing(stealing);
+        play_head
+    }
 
-impl Trig {
-    fn new() -> Self {
-        Trig {
-            inc: 0.0,
-            phase: 0.0,
-            sample_rate: 0.0,
-            chaos: 1.0,
-            rnd: 1.0,
+    fn update(play_head: &mut PlayHead, windows: &WindowTables, samples: usize) {
+        for _ in 0..samples {
+            play_head.update(windows);
         }
     }
 
-    fn set_inc(&mut self, freq: f32) {
-        self.inc = freq / self.sample_rate;
+    fn active_grain_num(play_head: &PlayHead) -> usize {
+        play_head.grains.iter().filter(|grain| grain.active).count()
     }
 
-    pub fn set_sample_rate(&mut self, sample_rate: f32) {
-        self.sample_rate = sample_rate;
+    #[test]
+    fn dropping_keeps_the_playing_grains() {
+        let mut play_head = play_head(2, Stealing::DropNew);
+        play_head.burst(3);
+
+        assert_eq!(active_grain_num(&play_head), 2);
+        assert!(play_head.grains.iter().all(|grain| !grain.releasing));
     }
 
-    fn update(&mut self) -> bool {
-        self.phase += self.inc;
-        if self.phase > 1.0 + self.rnd {
-            self.rnd = (rand::random::<f32>() - 0.5) * 2.0 * self.chaos;
-            self.phase -= 1.0 + self.rnd;
-            return true;
-        }
-        false
+    #[test]
+    fn oldest_steals_the_longest_playing_grain() {
+        let windows = WindowTables::new();
+        let mut play_head = play_head(2, Stealing::Oldest);
+        play_head.burst(1);
+        update(&mut play_head, &windows, 10);
+        play_head.burst(1);
+        update(&mut play_head, &windows, 10);
+        play_head.burst(1);
+
+        assert!(play_head.grains[0].releasing);
+        assert!(!play_head.grains[1].releasing);
+        assert_eq!(play_head.playing_grain_num(), 2);
+    }
+
+    #[test]
+    fn quietest_spares_grains_in_their_attack() {
+        let windows = WindowTables::new();
+        let mut play_head = play_head(2, Stealing::Quietest);
+        play_head.burst(1);
+        // past the peak of the sine window, about half its level
+        update(&mut play_head, &windows, 85);
+        play_head.burst(1);
+        // the new grain has barely started and is still quieter
+        update(&mut play_head, &windows, 1);
+        assert!(play_head.grains[1].gain < play_head.grains[0].gain);
+        play_head.burst(1);
+
+        assert!(play_head.grains[0].releasing);
+        assert!(!play_head.grains[1].releasing);
+    }
+
+    #[test]
+    fn quietest_steals_the_quietest_decaying_grain() {
+        let windows = WindowTables::new();
+        let mut play_head = play_head(2, Stealing::Quietest);
+        play_head.burst(1);
+        update(&mut play_head, &windows, 30);
+        play_head.burst(1);
+        // both past the peak, the second one is louder despite its lower init gain
+        update(&mut play_head, &windows, 60);
+        play_head.burst(1);
+
+        assert!(play_head.grains[0].releasing);
+        assert!(!play_head.grains[1].releasing);
+    }
+
+    #[test]
+    fn stolen_grains_fade_out_in_release_slots() {
+        let windows = WindowTables::new();
+        let mut play_head = play_head(1, Stealing::Oldest);
+        play_head.burst(1 + RELEASE_SLOTS);
+        assert_eq!(active_grain_num(&play_head), 1 + RELEASE_SLOTS);
+        assert_eq!(play_head.playing_grain_num(), 1);
+
+        // every slot is fading out, the playing grain is kept and the new one dropped
+        play_head.burst(1);
+        assert_eq!(play_head.playing_grain_num(), 1);
+        assert!(!play_head.grains[RELEASE_SLOTS].releasing);
+
+        update(&mut play_head, &windows, 10);
+        assert_eq!(active_grain_num(&play_head), 1);
+        assert_eq!(play_head.playing_grain_num(), 1);
     }
 }
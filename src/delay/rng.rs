@@ -0,0 +1,98 @@
+// xorshift64* generator, seedable so offline renders come out identical
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        let mut rng = Self { state: 0 };
+        rng.seed(seed);
+        rng
+    }
+
+    pub fn seed(&mut self, seed: u64) {
+        // splitmix64 spreads neighbouring seeds apart and never yields the forbidden zero state
+        let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        self.state = (z ^ (z >> 31)).max(1);
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 32) as u32
+    }
+
+    // range 0-1, 1 excluded
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / (1u32 << 24) as f32
+    }
+
+    // range -1 to 1
+    pub fn next_bipolar(&mut self) -> f32 {
+        self.next_f32() * 2.0 - 1.0
+    }
+
+    // exponential distribution with a mean of 1
+    pub fn next_exponential(&mut self) -> f32 {
+        -(1.0 - self.next_f32()).ln()
+    }
+
+    // standard normal distribution, box muller
+    pub fn next_gaussian(&mut self) -> f32 {
+        let radius = (-2.0 * (1.0 - self.next_f32()).ln()).sqrt();
+        radius * (std::f32::consts::TAU * self.next_f32()).cos()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sequence(rng: &mut Rng) -> Vec<u32> {
+        (0..100).map(|_| rng.next_u32()).collect()
+    }
+
+    #[test]
+    fn same_seed_same_sequence() {
+        assert_eq!(sequence(&mut Rng::new(42)), sequence(&mut Rng::new(42)));
+    }
+
+    #[test]
+    fn reseeding_restarts_the_sequence() {
+        let mut rng = Rng::new(7);
+        let first = sequence(&mut rng);
+        rng.seed(7);
+        assert_eq!(sequence(&mut rng), first);
+    }
+
+    #[test]
+    fn neighbouring_seeds_differ() {
+        let sequences: Vec<_> = (0..16).map(|seed| sequence(&mut Rng::new(seed))).collect();
+        for (i, a) in sequences.iter().enumerate() {
+            assert!(sequences[i + 1..].iter().all(|b| a != b));
+        }
+    }
+
+    #[test]
+    fn ranges() {
+        let mut rng = Rng::new(3);
+        for _ in 0..10000 {
+            assert!((0.0..1.0).contains(&rng.next_f32()));
+            assert!((-1.0..1.0).contains(&rng.next_bipolar()));
+            assert!(rng.next_exponential() >= 0.0);
+            assert!(rng.next_gaussian().is_finite());
+        }
+    }
+
+    #[test]
+    fn means() {
+        let mut rng = Rng::new(5);
+        let mean = |f: &mut dyn FnMut() -> f32| (0..100000).map(|_| f()).sum::<f32>() / 100000.0;
+        assert!((mean(&mut || rng.next_f32()) - 0.5).abs() < 0.01);
+        assert!((mean(&mut || rng.next_exponential()) - 1.0).abs() < 0.02);
+        assert!(mean(&mut || rng.next_gaussian()).abs() < 0.02);
+    }
+}
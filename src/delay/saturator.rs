@@ -0,0 +1,163 @@
+use nih_plug::prelude::Enum;
+use std::f32::consts::{FRAC_2_PI, FRAC_PI_2, PI};
+
+const HALF_BAND_TAPS: usize = 31;
+const DC_BLOCK_COEFF: f32 = 0.995;
+
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SaturationMode {
+    Off,
+    Tanh,
+    Tape,
+    // asymmetric, the negative half clips at -0.5
+    Diode,
+}
+
+impl SaturationMode {
+    // all curves have a slope of 1 around 0
+    fn shape(self, x: f32) -> f32 {
+        match self {
+            SaturationMode::Off => x,
+            SaturationMode::Tanh => x.tanh(),
+            SaturationMode::Tape => FRAC_2_PI * (FRAC_PI_2 * x).atan(),
+            SaturationMode::Diode => {
+                if x >= 0.0 {
+                    1.0 - (-x).exp()
+                } else {
+                    ((2.0 * x).exp() - 1.0) * 0.5
+                }
+            }
+        }
+    }
+}
+
+// windowed sinc half band lowpass at twice the sample rate
+struct HalfBand {
+    coeffs: [f32; HALF_BAND_TAPS],
+    history: [f32; HALF_BAND_TAPS],
+    index: usize,
+}
+
+impl HalfBand {
+    fn new() -> Self {
+        let center = (HALF_BAND_TAPS - 1) as f32 / 2.0;
+        let mut coeffs = [0.0; HALF_BAND_TAPS];
+        coeffs.iter_mut().enumerate().for_each(|(n, coeff)| {
+            let x = n as f32 - center;
+            let sinc = if x == 0.0 {
+                0.5
+            } else {
+                (0.5 * PI * x).sin() / (PI * x)
+            };
+            let phase = 2.0 * PI * n as f32 / (HALF_BAND_TAPS - 1) as f32;
+            let blackman = 0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos();
+            *coeff = sinc * blackman;
+        });
+
+        Self {
+            coeffs,
+            history: [0.0; HALF_BAND_TAPS],
+            index: 0,
+        }
+    }
+
+    fn push(&mut self, input: f32) {
+        self.index = (self.index + 1) % HALF_BAND_TAPS;
+        self.history[self.index] = input;
+    }
+
+    fn reset(&mut self) {
+        self.history = [0.0; HALF_BAND_TAPS];
+        self.index = 0;
+    }
+
+    fn output(&self) -> f32 {
+        self.coeffs
+            .iter()
+            .enumerate()
+            .map(|(n, coeff)| {
+                coeff * self.history[(self.index + HALF_BAND_TAPS - n) % HALF_BAND_TAPS]
+            })
+            .sum()
+    }
+}
+
+// runs the curve at twice the sample rate to keep the aliasing of high drive settings down
+struct Oversampler {
+    up: HalfBand,
+    down: HalfBand,
+    dc_in: f32,
+    dc_out: f32,
+}
+
+impl Oversampler {
+    fn new() -> Self {
+        Self {
+            up: HalfBand::new(),
+            down: HalfBand::new(),
+            dc_in: 0.0,
+            dc_out: 0.0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.up.reset();
+        self.down.reset();
+        self.dc_in = 0.0;
+        self.dc_out = 0.0;
+    }
+
+    fn process(&mut self, input: f32, mode: SaturationMode, drive: f32) -> f32 {
+        // zero stuffing halves the level, so the first sample makes up for it
+        self.up.push(input * 2.0);
+        let first = mode.shape(self.up.output() * drive) / drive;
+        self.up.push(0.0);
+        let second = mode.shape(self.up.output() * drive) / drive;
+
+        self.down.push(first);
+        self.down.push(second);
+        let output = self.down.output();
+
+        // the asymmetric curves add an offset that would build up in the loop
+        self.dc_out = output - self.dc_in + DC_BLOCK_COEFF * self.dc_out;
+        self.dc_in = output;
+        self.dc_out
+    }
+}
+
+pub struct Saturator {
+    mode: SaturationMode,
+    drive: f32, // linear gain into the curve
+    oversamplers: (Oversampler, Oversampler),
+}
+
+impl Saturator {
+    pub fn new() -> Self {
+        Self {
+            mode: SaturationMode::Off,
+            drive: 1.0,
+            oversamplers: (Oversampler::new(), Oversampler::new()),
+        }
+    }
+
+    pub fn set(&mut self, mode: SaturationMode, drive: f32) {
+        self.mode = mode;
+        self.drive = drive;
+    }
+
+    pub fn reset(&mut self) {
+        self.oversamplers.0.reset();
+        self.oversamplers.1.reset();
+    }
+
+    pub fn process(&mut self, input: (f32, f32)) -> (f32, f32) {
+        if self.mode == SaturationMode::Off {
+            return input;
+        }
+
+        (
+            self.oversamplers.0.process(input.0, self.mode, self.drive),
+            self.oversamplers.1.process(input.1, self.mode, self.drive),
+        )
+    }
+}
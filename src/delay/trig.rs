@@ -0,0 +1,226 @@
+use super::rng::Rng;
+use nih_plug::prelude::Enum;
+
+const JITTER_DEVIATION: f32 = 1.0 / 6.0; // in periods at full chaos
+const MAX_JITTER: f32 = 0.45; // keeps jittered grains in the order of their grid points
+
+// how chaos spreads the grains of a playhead in time, the average rate is always the density
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TriggerMode {
+    // exact period, chaos has no effect
+    Synchronous,
+    // chaos blends from a fixed period to exponentially distributed gaps, which at full chaos
+    // is a poisson process
+    #[name = "Asynchronous"]
+    Poisson,
+    // every grain is moved away from its grid point by a normal distributed offset, chaos sets
+    // the deviation up to a sixth of the period. synced, the grid points are the note grid
+    #[name = "Gaussian Jitter"]
+    Jitter,
+}
+
+pub struct Trig {
+    inc: f32,
+    phase: f32, // position between grid points, for Poisson the time since the last grain
+    sample_rate: f32,
+    pub chaos: f32,
+    pub mode: TriggerMode,
+    // synced triggers stay on the note grid. Poisson has no grid and fires exactly on it
+    pub synced: bool,
+    interval: f32,     // Poisson: gap until the next grain in periods
+    offset: f32,       // Jitter: offset of the grain of the next grid point in periods
+    fired: bool,       // Jitter: the grain of the next grid point already started early
+    late: Option<f32>, // Jitter: offset of a grain that starts after its grid point
+}
+
+impl Trig {
+    pub fn new() -> Self {
+        Trig {
+            inc: 0.0,
+            phase: 0.0,
+            sample_rate: 0.0,
+            chaos: 1.0,
+            mode: TriggerMode::Jitter,
+            synced: false,
+            interval: 1.0,
+            offset: 0.0,
+            fired: false,
+            late: None,
+        }
+    }
+
+    pub fn set_inc(&mut self, freq: f32) {
+        self.inc = freq / self.sample_rate;
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+    }
+
+    pub fn reset(&mut self) {
+        self.phase = 0.0;
+        self.interval = 1.0;
+        self.offset = 0.0;
+        self.fired = false;
+        self.late = None;
+    }
+
+    // phase is the position on the note grid the next sample will be at
+    pub fn align(&mut self, phase: f32) {
+        // Poisson has no grid to align to
+        if self.mode == TriggerMode::Poisson && !self.synced {
+            return;
+        }
+
+        let phase = phase - self.inc;
+        // the grid line lies between the last and the next sample and has not been triggered yet
+        self.phase = if phase < 0.0 && self.phase > 0.5 {
+            phase + 1.0
+        } else {
+            phase
+        };
+    }
+
+    // speed scales the rate, the lfos use it for density
+    pub fn update(&mut self, rng: &mut Rng, speed: f32) -> bool {
+        self.phase += self.inc * speed;
+        match self.mode {
+            TriggerMode::Synchronous => self.update_synchronous(),
+            TriggerMode::Poisson if self.synced => self.update_synchronous(),
+            TriggerMode::Poisson => self.update_poisson(rng),
+            TriggerMode::Jitter => self.update_jitter(rng),
+        }
+    }
+
+    fn update_synchronous(&mut self) -> bool {
+        if self.phase >= 1.0 {
+            self.phase -= 1.0;
+            return true;
+        }
+        false
+    }
+
+    fn update_poisson(&mut self, rng: &mut Rng) -> bool {
+        if self.phase >= self.interval {
+            self.phase -= self.interval;
+            self.interval = (1.0 - self.chaos) + self.chaos * rng.next_exponential();
+            return true;
+        }
+        false
+    }
+
+    fn update_jitter(&mut self, rng: &mut Rng) -> bool {
+        let mut trigger = false;
+
+        // grains with a negative offset start before their grid point
+        if !self.fired && self.offset < 0.0 && self.phase >= 1.0 + self.offset {
+            self.fired = true;
+            trigger = true;
+        }
+
+        if self.phase >= 1.0 {
+            self.phase -= 1.0;
+            if !self.fired {
+                self.late = Some(self.offset);
+            }
+            self.offset = (rng.next_gaussian() * self.chaos * JITTER_DEVIATION)
+                .clamp(-MAX_JITTER, MAX_JITTER);
+            self.fired = false;
+        }
+
+        if let Some(late) = self.late {
+            if self.phase >= late {
+                self.late = None;
+                trigger = true;
+            }
+        }
+
+        trigger
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // sample indices of the triggers, a period of 128 samples
+    fn triggers(trig: &mut Trig, rng: &mut Rng) -> Vec<usize> {
+        trig.set_sample_rate(1024.0);
+        trig.set_inc(8.0);
+        (0..1024).filter(|_| trig.update(rng, 1.0)).collect()
+    }
+
+    #[test]
+    fn synced_triggers_are_exact_without_jitter() {
+        let mut rng = Rng::new(0);
+        for mode in [TriggerMode::Synchronous, TriggerMode::Poisson] {
+            let mut trig = Trig::new();
+            trig.mode = mode;
+            trig.chaos = 1.0;
+            trig.synced = true;
+            let indices = triggers(&mut trig, &mut rng);
+            assert_eq!(indices.len(), 8, "{mode:?}");
+            assert!(
+                indices.windows(2).all(|pair| pair[1] - pair[0] == 128),
+                "{mode:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn synced_jitter_moves_triggers_around_the_grid() {
+        let mut rng = Rng::new(0);
+        let mut trig = Trig::new();
+        trig.mode = TriggerMode::Jitter;
+        trig.chaos = 1.0;
+        trig.synced = true;
+        let indices = triggers(&mut trig, &mut rng);
+        // distance to the closest grid point, the phase reaches a grid point every 128 samples
+        let distances: Vec<_> = indices
+            .iter()
+            .map(|index| {
+                let offset = (index + 1) % 128;
+                offset.min(128 - offset)
+            })
+            .collect();
+
+        assert!(distances.iter().any(|distance| *distance > 0));
+        assert!(distances
+            .iter()
+            .all(|distance| *distance as f32 <= MAX_JITTER * 128.0 + 1.0));
+    }
+
+    #[test]
+    fn synced_triggers_align_to_the_grid_in_every_mode() {
+        let mut rng = Rng::new(0);
+        for mode in [
+            TriggerMode::Synchronous,
+            TriggerMode::Poisson,
+            TriggerMode::Jitter,
+        ] {
+            let mut trig = Trig::new();
+            trig.mode = mode;
+            trig.synced = true;
+            trig.set_sample_rate(1024.0);
+            trig.set_inc(8.0);
+            // half way between two grid points, the next one is 64 samples away
+            trig.align(0.5);
+            let indices: Vec<_> = (0..1024).filter(|_| trig.update(&mut rng, 1.0)).collect();
+            assert_eq!(indices.first(), Some(&64), "{mode:?}");
+        }
+    }
+
+    #[test]
+    fn chaos_moves_unsynced_triggers() {
+        let mut rng = Rng::new(0);
+        for mode in [TriggerMode::Poisson, TriggerMode::Jitter] {
+            let mut trig = Trig::new();
+            trig.mode = mode;
+            let indices = triggers(&mut trig, &mut rng);
+            assert!(
+                indices.windows(2).any(|pair| pair[1] - pair[0] != 128),
+                "{mode:?}"
+            );
+        }
+    }
+}
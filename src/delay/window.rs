@@ -0,0 +1,102 @@
+use nih_plug::prelude::Enum;
+use std::f32::consts::PI;
+
+const TABLE_SIZE: usize = 1024;
+const GAUSS_SIGMA: f32 = 0.15;
+const EXP_ATTACK: f32 = 0.02;
+const EXP_DECAY: f32 = 6.0;
+const MIN_TAPER: f32 = 0.005;
+
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum WindowShape {
+    #[default]
+    Sine,
+    Hann,
+    Tukey,
+    Trapezoid,
+    Gaussian,
+    #[name = "Percussive"]
+    Exponential,
+    #[name = "Reverse Percussive"]
+    ReverseExponential,
+}
+
+// shape and flat top a grain was spawned with
+#[derive(Clone, Copy, Default)]
+pub struct Window {
+    pub shape: WindowShape,
+    pub flat: f32, // flat top of tukey and trapezoid range 0-1
+}
+
+pub struct WindowTables {
+    sine: Vec<f32>,
+    hann: Vec<f32>,
+    gaussian: Vec<f32>,
+    exponential: Vec<f32>,
+}
+
+impl WindowTables {
+    pub fn new() -> Self {
+        let g0 = gaussian(0.0);
+        let e1 = (-EXP_DECAY).exp();
+
+        Self {
+            sine: table(|x| (x * PI).sin()),
+            hann: table(|x| 0.5 * (1.0 - (2.0 * PI * x).cos())),
+            gaussian: table(|x| (gaussian(x) - g0) / (1.0 - g0)),
+            exponential: table(|x| {
+                if x < EXP_ATTACK {
+                    x / EXP_ATTACK
+                } else {
+                    let t = (x - EXP_ATTACK) / (1.0 - EXP_ATTACK);
+                    ((-EXP_DECAY * t).exp() - e1) / (1.0 - e1)
+                }
+            }),
+        }
+    }
+
+    // phase range 0-1
+    pub fn get(&self, window: Window, phase: f32) -> f32 {
+        let phase = phase.clamp(0.0, 1.0);
+        match window.shape {
+            WindowShape::Sine => lookup(&self.sine, phase),
+            WindowShape::Hann => lookup(&self.hann, phase),
+            WindowShape::Tukey => {
+                let taper = ((1.0 - window.flat) * 0.5).max(MIN_TAPER);
+                if phase < taper {
+                    lookup(&self.hann, 0.5 * phase / taper)
+                } else if phase > 1.0 - taper {
+                    lookup(&self.hann, 1.0 - 0.5 * (1.0 - phase) / taper)
+                } else {
+                    1.0
+                }
+            }
+            WindowShape::Trapezoid => {
+                let taper = ((1.0 - window.flat) * 0.5).max(MIN_TAPER);
+                (phase.min(1.0 - phase) / taper).min(1.0)
+            }
+            WindowShape::Gaussian => lookup(&self.gaussian, phase),
+            WindowShape::Exponential => lookup(&self.exponential, phase),
+            WindowShape::ReverseExponential => lookup(&self.exponential, 1.0 - phase),
+        }
+    }
+}
+
+fn gaussian(x: f32) -> f32 {
+    let d = (x - 0.5) / GAUSS_SIGMA;
+    (-0.5 * d * d).exp()
+}
+
+fn table(f: impl Fn(f32) -> f32) -> Vec<f32> {
+    // one extra point so lookup can interpolate up to phase 1.0
+    (0..=TABLE_SIZE)
+        .map(|i| f(i as f32 / TABLE_SIZE as f32))
+        .collect()
+}
+
+fn lookup(table: &[f32], phase: f32) -> f32 {
+    let pos = phase * TABLE_SIZE as f32;
+    let index = (pos as usize).min(TABLE_SIZE - 1);
+    let t = pos - index as f32;
+    table[index] + (table[index + 1] - table[index]) * t
+}
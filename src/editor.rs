@@ -1,34 +1,87 @@
+use lfo_meter::LfoMeter;
 use nih_plug::nih_error;
-use nih_plug::prelude::Editor;
+use nih_plug::params::internals::ParamPtr;
+use nih_plug::prelude::{Editor, Param};
 use nih_plug_vizia::vizia::prelude::*;
 use nih_plug_vizia::widgets::*;
 use nih_plug_vizia::{assets, create_vizia_editor, ViziaState, ViziaTheming};
-use triple_buffer::Output;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use waveform::Waveform;
 
-use crate::delay::DrawData;
+use crate::delay::{DrawData, LFO_NUM, PLAY_HEADS};
 use crate::GranularDelayParams;
+mod lfo_meter;
 mod waveform;
 
-const RED: (u8, u8, u8) = (201, 104, 104);
-const GREEN: (u8, u8, u8) = (165, 182, 141);
+const NAMES: [&str; PLAY_HEADS] = [
+    "Playhead A",
+    "Playhead B",
+    "Playhead C",
+    "Playhead D",
+    "Playhead E",
+    "Playhead F",
+    "Playhead G",
+    "Playhead H",
+];
+const COLORS: [(u8, u8, u8); PLAY_HEADS] = [
+    (201, 104, 104),
+    (165, 182, 141),
+    (125, 157, 201),
+    (214, 181, 108),
+    (171, 135, 196),
+    (110, 186, 181),
+    (212, 143, 102),
+    (190, 190, 190),
+];
 
 #[derive(Lens)]
 struct Data {
     params: Arc<GranularDelayParams>,
+    selected: usize,     // playhead shown in the controls
+    selected_lfo: usize, // lfo shown in the controls
 }
 
-impl Model for Data {}
+enum EditorEvent {
+    Select(usize),
+    SelectLfo(usize),
+    Learn(ParamPtr),
+    Unbind(u8),
+    SetMin(u8, f32),
+    SetMax(u8, f32),
+    SetCurve(u8, f32),
+    PassOnControllers,
+}
+
+impl Model for Data {
+    fn event(&mut self, cx: &mut EventContext, event: &mut Event) {
+        event.map(|editor_event, _| match editor_event {
+            EditorEvent::Select(index) => self.selected = *index,
+            EditorEvent::SelectLfo(index) => self.selected_lfo = *index,
+            EditorEvent::Learn(param) => self.params.midi_learn.learn(*param),
+            EditorEvent::Unbind(cc) => self.params.midi_learn.unbind(*cc),
+            EditorEvent::SetMin(cc, value) => self.params.midi_learn.set_min(*cc, *value),
+            EditorEvent::SetMax(cc, value) => self.params.midi_learn.set_max(*cc, *value),
+            EditorEvent::SetCurve(cc, value) => self.params.midi_learn.set_curve(*cc, *value),
+            // the host records controller moves like any other change, and the sliders show them
+            EditorEvent::PassOnControllers => {
+                for (param, value, _) in self.params.midi_learn.values() {
+                    cx.emit(RawParamEvent::BeginSetParameter(param));
+                    cx.emit(RawParamEvent::SetParameterNormalized(param, value));
+                    cx.emit(RawParamEvent::EndSetParameter(param));
+                }
+            }
+        });
+    }
+}
 
 pub(crate) fn default_state() -> Arc<ViziaState> {
-    ViziaState::new(|| (700, 750))
+    ViziaState::new(|| (800, 750))
 }
 
 pub(crate) fn create(
     params: Arc<GranularDelayParams>,
     editor_state: Arc<ViziaState>,
-    draw_data: Arc<Mutex<Output<DrawData>>>,
+    draw_data: Arc<DrawData>,
 ) -> Option<Box<dyn Editor>> {
     create_vizia_editor(editor_state, ViziaTheming::Custom, move |cx, _| {
         assets::register_noto_sans_light(cx);
@@ -40,26 +93,37 @@ pub(crate) fn create(
 
         Data {
             params: params.clone(),
+            selected: 0,
+            selected_lfo: 0,
         }
         .build(cx);
 
+        Binding::new(
+            cx,
+            Data::params.map(|params| params.midi_learn.moved()),
+            |cx, _| cx.emit(EditorEvent::PassOnControllers),
+        );
+
+        // the top bar stays in place while the rest scrolls
         VStack::new(cx, |cx| {
             top_bar(cx);
-            controlls(cx);
-            waveform(cx, draw_data.clone());
+            ScrollView::new(cx, 0.0, 0.0, false, true, |cx| {
+                VStack::new(cx, |cx| {
+                    playhead_tabs(cx);
+                    controlls(cx);
+                    lfos(cx, draw_data.clone());
+                    waveform(cx, draw_data.clone());
+                    midi_mappings(cx);
+                })
+                .height(Auto);
+            });
         });
     })
 }
 
-fn waveform(cx: &mut Context, draw_data: Arc<Mutex<Output<DrawData>>>) {
+fn waveform(cx: &mut Context, draw_data: Arc<DrawData>) {
     HStack::new(cx, |cx| {
-        Waveform::new(
-            cx,
-            draw_data,
-            Data::params,
-            |params| &params.playhead_a.distance,
-            |params| &params.playhead_b.distance,
-        );
+        Waveform::new(cx, draw_data);
     })
     .min_top(Pixels(30.0))
     .left(Pixels(15.0))
@@ -68,6 +132,77 @@ fn waveform(cx: &mut Context, draw_data: Arc<Mutex<Output<DrawData>>>) {
     .class("waveform");
 }
 
+// the list is rebuilt whenever a controller is bound or removed
+fn midi_mappings(cx: &mut Context) {
+    VStack::new(cx, |cx| {
+        section_label(cx, "MIDI Learn", None);
+        Label::new(
+            cx,
+            "Right click a slider or button and move a controller to bind it",
+        );
+
+        HStack::new(cx, |cx| {
+            Label::new(cx, "Controller").width(Pixels(300.0));
+            Label::new(cx, "Min").width(Pixels(100.0));
+            Label::new(cx, "Max").width(Pixels(100.0));
+            Label::new(cx, "Curve").width(Pixels(100.0));
+        })
+        .col_between(Pixels(10.0))
+        .top(Pixels(10.0))
+        .height(Pixels(20.0));
+
+        ScrollView::new(cx, 0.0, 0.0, false, true, |cx| {
+            Binding::new(
+                cx,
+                Data::params.map(|params| params.midi_learn.version()),
+                |cx, _| {
+                    for (cc, name) in Data::params.get(cx).midi_learn.bound() {
+                        mapping_row(cx, cc, name);
+                    }
+                },
+            );
+        })
+        .height(Pixels(150.0));
+    })
+    .left(Pixels(15.0))
+    .right(Pixels(15.0))
+    .top(Pixels(15.0))
+    .height(Auto);
+}
+
+fn mapping_row(cx: &mut Context, cc: u8, name: String) {
+    HStack::new(cx, |cx| {
+        Label::new(cx, format!("CC {cc}  {name}")).width(Pixels(300.0));
+        Slider::new(
+            cx,
+            Data::params.map(move |params| params.midi_learn.min(cc)),
+        )
+        .on_changing(move |cx, value| cx.emit(EditorEvent::SetMin(cc, value)))
+        .width(Pixels(100.0));
+        Slider::new(
+            cx,
+            Data::params.map(move |params| params.midi_learn.max(cc)),
+        )
+        .on_changing(move |cx, value| cx.emit(EditorEvent::SetMax(cc, value)))
+        .width(Pixels(100.0));
+        Slider::new(
+            cx,
+            Data::params.map(move |params| params.midi_learn.curve(cc)),
+        )
+        .range(-1.0..1.0)
+        .on_changing(move |cx, value| cx.emit(EditorEvent::SetCurve(cc, value)))
+        .width(Pixels(100.0));
+        Button::new(
+            cx,
+            move |cx| cx.emit(EditorEvent::Unbind(cc)),
+            |cx| Label::new(cx, "Remove"),
+        )
+        .class("remove");
+    })
+    .col_between(Pixels(10.0))
+    .height(Pixels(30.0));
+}
+
 fn top_bar(cx: &mut Context) {
     HStack::new(cx, |cx| {
         Label::new(cx, "Oh-My-Grain")
@@ -83,119 +218,347 @@ fn top_bar(cx: &mut Context) {
     .width(Stretch(1.0));
 }
 
+fn playhead_tabs(cx: &mut Context) {
+    HStack::new(cx, |cx| {
+        for (index, name) in NAMES.iter().enumerate() {
+            let (r, g, b) = COLORS[index];
+            Button::new(
+                cx,
+                move |cx| cx.emit(EditorEvent::Select(index)),
+                move |cx| Label::new(cx, *name).color(Color::rgb(r, g, b)),
+            )
+            .class("tab")
+            .toggle_class(
+                "selected",
+                Data::selected.map(move |selected| *selected == index),
+            );
+        }
+    })
+    .left(Pixels(15.0))
+    .right(Pixels(15.0))
+    .bottom(Pixels(15.0))
+    .col_between(Pixels(5.0))
+    .height(Auto);
+}
+
 fn controlls(cx: &mut Context) {
     HStack::new(cx, |cx| {
-            VStack::new(cx, |cx| {
-                Label::new(cx, "Playhead A")
-                    .font_family(vec![FamilyOwned::Name(String::from(assets::NOTO_SANS))])
-                    .font_weight(FontWeightKeyword::Medium)
-                    .font_size(15.0)
-                    .height(Pixels(20.0))
-                    .child_top(Stretch(1.0))
-                    .child_bottom(Pixels(0.0))
-                    .color(Color::rgb(RED.0, RED.1, RED.2));
-
-                Label::new(cx, "Distance");
-                ParamSlider::new(cx, Data::params, |params| &params.playhead_a.distance)
-                    .bottom(Pixels(10.0))
-                    .set_style(ParamSliderStyle::FromLeft);
-                Label::new(cx, "Window Size");
-                ParamSlider::new(cx, Data::params, |params| &params.playhead_a.window_size)
-                    .bottom(Pixels(10.0))
-                    .set_style(ParamSliderStyle::FromLeft);
-                Label::new(cx, "Grain Length");
-                ParamSlider::new(cx, Data::params, |params| &params.playhead_a.grain_size)
-                    .bottom(Pixels(10.0))
-                    .set_style(ParamSliderStyle::FromLeft);
-                Label::new(cx, "Density");
-                ParamSlider::new(cx, Data::params, |params| &params.playhead_a.density)
-                    .bottom(Pixels(10.0))
-                    .set_style(ParamSliderStyle::FromLeft);
-                Label::new(cx, "Pitch");
-                ParamSlider::new(cx, Data::params, |params| &params.playhead_a.pitch)
-                    .bottom(Pixels(10.0))
-                    .set_style(ParamSliderStyle::FromLeft);
-                Label::new(cx, "Gain");
-                ParamSlider::new(cx, Data::params, |params| &params.playhead_a.gain)
-                    .bottom(Pixels(10.0))
-                    .set_style(ParamSliderStyle::FromLeft);
-                Label::new(cx, "Chaos");
-                ParamSlider::new(cx, Data::params, |params| &params.playhead_a.chaos)
-                    .bottom(Pixels(10.0))
-                    .set_style(ParamSliderStyle::FromLeft);
-            })
-            .height(Auto);
-
-            VStack::new(cx, |cx| {
-                Label::new(cx, "Playhead B")
-                    .font_family(vec![FamilyOwned::Name(String::from(assets::NOTO_SANS))])
-                    .font_weight(FontWeightKeyword::Medium)
-                    .font_size(15.0)
-                    .height(Pixels(20.0))
-                    .child_top(Stretch(1.0))
-                    .child_bottom(Pixels(0.0))
-                    .color(Color::rgb(GREEN.0, GREEN.1, GREEN.2));
-
-                Label::new(cx, "Distance");
-                ParamSlider::new(cx, Data::params, |params| &params.playhead_b.distance)
-                    .bottom(Pixels(10.0))
-                    .set_style(ParamSliderStyle::FromLeft);
-                Label::new(cx, "Window Size");
-                ParamSlider::new(cx, Data::params, |params| &params.playhead_b.window_size)
-                    .bottom(Pixels(10.0))
-                    .set_style(ParamSliderStyle::FromLeft);
-                Label::new(cx, "Grain Length");
-                ParamSlider::new(cx, Data::params, |params| &params.playhead_b.grain_size)
-                    .bottom(Pixels(10.0))
-                    .set_style(ParamSliderStyle::FromLeft);
-                Label::new(cx, "Density");
-                ParamSlider::new(cx, Data::params, |params| &params.playhead_b.density)
-                    .bottom(Pixels(10.0))
-                    .set_style(ParamSliderStyle::FromLeft);
-                Label::new(cx, "Pitch");
-                ParamSlider::new(cx, Data::params, |params| &params.playhead_b.pitch)
-                    .bottom(Pixels(10.0))
-                    .set_style(ParamSliderStyle::FromLeft);
-                Label::new(cx, "Gain");
-                ParamSlider::new(cx, Data::params, |params| &params.playhead_b.gain)
-                    .bottom(Pixels(10.0))
+        // rebuilds the playhead columns whenever another playhead is selected
+        Binding::new(cx, Data::selected, |cx, selected| {
+            playhead_controlls(cx, selected.get(cx));
+        });
+
+        VStack::new(cx, |cx| {
+            section_label(cx, "Main", None);
+
+            param_slider(cx, "Feedback", |params| &params.feedback);
+            param_slider(cx, "Filter Mode", |params| &params.filter_mode);
+            param_slider(cx, "Color", |params| &params.color);
+            param_slider(cx, "Resonance", |params| &params.resonance);
+            param_slider(cx, "Saturation", |params| &params.saturation);
+            param_slider(cx, "Drive", |params| &params.drive);
+            param_slider(cx, "Dry", |params| &params.dry);
+            param_slider(cx, "Wet", |params| &params.wet);
+            param_slider(cx, "Pan Law", |params| &params.pan_law);
+            param_slider(cx, "Stereo Mode", |params| &params.stereo_mode);
+            param_slider(cx, "Buffer Length", |params| &params.buffer_length);
+            param_slider(cx, "Quality", |params| &params.quality);
+            param_slider(cx, "Seed", |params| &params.seed);
+            learn_button(cx, |params| &params.lock_randomness)
+                .width(Pixels(180.0))
+                .top(Pixels(10.0));
+            learn_button(cx, |params| &params.freeze)
+                .width(Pixels(180.0))
+                .top(Pixels(20.0))
+                .bottom(Pixels(10.0));
+
+            section_label(cx, "MIDI", None);
+
+            param_slider(cx, "MIDI Mode", |params| &params.midi_mode);
+            param_slider(cx, "Playhead", |params| &params.midi_playhead);
+            param_slider(cx, "Root Note", |params| &params.root_note);
+            param_slider(cx, "Burst Size", |params| &params.burst_size);
+        })
+        .height(Auto);
+    })
+    .left(Pixels(15.0))
+    .right(Pixels(15.0))
+    .height(Auto);
+}
+
+fn lfos(cx: &mut Context, draw_data: Arc<DrawData>) {
+    VStack::new(cx, |cx| {
+        HStack::new(cx, |cx| {
+            for index in 0..LFO_NUM {
+                Button::new(
+                    cx,
+                    move |cx| cx.emit(EditorEvent::SelectLfo(index)),
+                    move |cx| Label::new(cx, format!("LFO {}", index + 1)),
+                )
+                .class("tab")
+                .toggle_class(
+                    "selected",
+                    Data::selected_lfo.map(move |selected| *selected == index),
+                );
+            }
+        })
+        .bottom(Pixels(15.0))
+        .col_between(Pixels(5.0))
+        .height(Auto);
+
+        // rebuilds the lfo columns whenever another lfo is selected
+        Binding::new(cx, Data::selected_lfo, move |cx, selected| {
+            lfo_controlls(cx, selected.get(cx), draw_data.clone());
+        });
+    })
+    .left(Pixels(15.0))
+    .right(Pixels(15.0))
+    .top(Pixels(15.0))
+    .height(Auto);
+}
+
+fn lfo_controlls(cx: &mut Context, index: usize, draw_data: Arc<DrawData>) {
+    HStack::new(cx, |cx| {
+        VStack::new(cx, |cx| {
+            param_slider(cx, "Shape", move |params| &params.lfos[index].shape);
+            param_slider(cx, "Target", move |params| &params.lfos[index].target);
+            param_slider(cx, "Playhead", move |params| &params.lfos[index].playhead);
+        })
+        .height(Auto);
+
+        VStack::new(cx, |cx| {
+            param_slider(cx, "Rate", move |params| &params.lfos[index].rate);
+            sync_row(
+                cx,
+                move |params| &params.lfos[index].rate_sync,
+                move |params| &params.lfos[index].rate_division,
+            );
+            Label::new(cx, "Depth");
+            learn_slider(cx, move |params| &params.lfos[index].depth)
+                .bottom(Pixels(10.0))
+                .set_style(ParamSliderStyle::Centered);
+        })
+        .height(Auto);
+
+        VStack::new(cx, |cx| {
+            Label::new(cx, "Output");
+            LfoMeter::new(cx, draw_data, index);
+        })
+        .height(Auto);
+    })
+    .height(Auto);
+}
+
+fn playhead_controlls(cx: &mut Context, index: usize) {
+    HStack::new(cx, |cx| {
+        VStack::new(cx, |cx| {
+            section_label(cx, NAMES[index], Some(COLORS[index]));
+
+            param_slider(cx, "Distance", move |params| {
+                &params.playheads[index].distance
+            });
+            sync_row(
+                cx,
+                move |params| &params.playheads[index].distance_sync,
+                move |params| &params.playheads[index].distance_division,
+            );
+            param_slider(cx, "Window Size", move |params| {
+                &params.playheads[index].window_size
+            });
+            param_slider(cx, "Window Shape", move |params| {
+                &params.playheads[index].window_shape
+            });
+            param_slider(cx, "Flat Top", move |params| {
+                &params.playheads[index].window_flat
+            });
+            param_slider(cx, "Grain Length", move |params| {
+                &params.playheads[index].grain_size
+            });
+            param_slider(cx, "Density", move |params| {
+                &params.playheads[index].density
+            });
+            sync_row(
+                cx,
+                move |params| &params.playheads[index].density_sync,
+                move |params| &params.playheads[index].density_division,
+            );
+            param_slider(cx, "Trigger Mode", move |params| {
+                &params.playheads[index].trigger_mode
+            });
+            param_slider(cx, "Chaos", move |params| &params.playheads[index].chaos);
+            param_slider(cx, "Grain Limit", move |params| {
+                &params.playheads[index].grain_limit
+            });
+            param_slider(cx, "Voice Stealing", move |params| {
+                &params.playheads[index].stealing
+            });
+        })
+        .height(Auto);
+
+        VStack::new(cx, |cx| {
+            section_label(cx, "Pitch", None);
+
+            param_slider(cx, "Pitch", move |params| &params.playheads[index].pitch);
+            Label::new(cx, "Fine");
+            learn_slider(cx, move |params| &params.playheads[index].fine)
+                .bottom(Pixels(10.0))
+                .set_style(ParamSliderStyle::Centered);
+            param_slider(cx, "Pitch Spread", move |params| {
+                &params.playheads[index].pitch_spread
+            });
+            param_slider(cx, "Harmony", move |params| {
+                &params.playheads[index].harmony
+            });
+            param_slider(cx, "Custom Intervals", move |params| {
+                &params.playheads[index].harmony_intervals
+            });
+            param_slider(cx, "Weighting", move |params| {
+                &params.playheads[index].harmony_weighting
+            });
+            HStack::new(cx, |cx| {
+                learn_slider(cx, move |params| &params.playheads[index].harmony_octaves)
+                    .width(Pixels(60.0))
                     .set_style(ParamSliderStyle::FromLeft);
-                Label::new(cx, "Chaos");
-                ParamSlider::new(cx, Data::params, |params| &params.playhead_b.chaos)
-                    .bottom(Pixels(10.0))
+                learn_slider(cx, move |params| &params.playheads[index].harmony_amount)
+                    .width(Pixels(115.0))
                     .set_style(ParamSliderStyle::FromLeft);
             })
-            .height(Auto);
+            .col_between(Pixels(5.0))
+            .height(Auto)
+            .bottom(Pixels(10.0));
+            param_slider(cx, "Reverse", move |params| {
+                &params.playheads[index].reverse
+            });
+        })
+        .height(Auto);
 
         VStack::new(cx, |cx| {
-            Label::new(cx, "Main")
-                .font_family(vec![FamilyOwned::Name(String::from(assets::NOTO_SANS))])
-                .font_weight(FontWeightKeyword::Thin)
-                .font_size(15.0)
-                .height(Pixels(20.0))
-                .child_top(Stretch(1.0))
-                .child_bottom(Pixels(0.0));
-
-            Label::new(cx, "Feedback");
-            ParamSlider::new(cx, Data::params, |params| &params.feedback)
-                .bottom(Pixels(10.0))
-                .set_style(ParamSliderStyle::FromLeft);
-            Label::new(cx, "Color");
-            ParamSlider::new(cx, Data::params, |params| &params.color)
-                .bottom(Pixels(10.0))
-                .set_style(ParamSliderStyle::FromLeft);
-            Label::new(cx, "Dry");
-            ParamSlider::new(cx, Data::params, |params| &params.dry)
-                .bottom(Pixels(10.0))
-                .set_style(ParamSliderStyle::FromLeft);
-            Label::new(cx, "Wet");
-            ParamSlider::new(cx, Data::params, |params| &params.wet)
+            section_label(cx, "Output", None);
+
+            learn_button(cx, move |params| &params.playheads[index].enabled)
+                .width(Pixels(180.0))
+                .top(Pixels(20.0))
+                .bottom(Pixels(10.0));
+            param_slider(cx, "Gain", move |params| &params.playheads[index].gain);
+            param_slider(cx, "Spread", move |params| &params.playheads[index].spread);
+            Label::new(cx, "Pan Center");
+            learn_slider(cx, move |params| &params.playheads[index].pan)
                 .bottom(Pixels(10.0))
-                .set_style(ParamSliderStyle::FromLeft);
+                .set_style(ParamSliderStyle::Centered);
+            param_slider(cx, "Source", move |params| &params.playheads[index].source);
+            param_slider(cx, "Feedback Source", move |params| {
+                &params.playheads[index].feedback_src
+            });
         })
         .height(Auto);
     })
-    .left(Pixels(15.0))
-    .right(Pixels(15.0))
     .height(Auto);
 }
+
+fn section_label(cx: &mut Context, text: &'static str, color: Option<(u8, u8, u8)>) {
+    let label = Label::new(cx, text)
+        .font_family(vec![FamilyOwned::Name(String::from(assets::NOTO_SANS))])
+        .font_size(15.0)
+        .height(Pixels(20.0))
+        .child_top(Stretch(1.0))
+        .child_bottom(Pixels(0.0));
+
+    match color {
+        Some((r, g, b)) => {
+            label
+                .font_weight(FontWeightKeyword::Medium)
+                .color(Color::rgb(r, g, b));
+        }
+        None => {
+            label.font_weight(FontWeightKeyword::Thin);
+        }
+    }
+}
+
+fn learn_slider<P, FMap>(cx: &mut Context, params_to_param: FMap) -> Handle<'_, ParamSlider>
+where
+    P: Param + 'static,
+    FMap: Fn(&Arc<GranularDelayParams>) -> &P + Copy + Send + Sync + 'static,
+{
+    let learnable = learnable(cx, params_to_param);
+    let slider = ParamSlider::new(cx, Data::params, params_to_param);
+    with_learn(slider, learnable, params_to_param)
+}
+
+fn learn_button<P, FMap>(cx: &mut Context, params_to_param: FMap) -> Handle<'_, ParamButton>
+where
+    P: Param + 'static,
+    FMap: Fn(&Arc<GranularDelayParams>) -> &P + Copy + Send + Sync + 'static,
+{
+    let learnable = learnable(cx, params_to_param);
+    let button = ParamButton::new(cx, Data::params, params_to_param);
+    with_learn(button, learnable, params_to_param)
+}
+
+fn learnable<P, FMap>(cx: &mut Context, params_to_param: FMap) -> bool
+where
+    P: Param + 'static,
+    FMap: Fn(&Arc<GranularDelayParams>) -> &P,
+{
+    let params = Data::params.get(cx);
+    params
+        .midi_learn
+        .learnable(params_to_param(&params).as_ptr())
+}
+
+// right click starts or stops learning a controller for the parameter
+fn with_learn<'a, V, P, FMap>(
+    handle: Handle<'a, V>,
+    learnable: bool,
+    params_to_param: FMap,
+) -> Handle<'a, V>
+where
+    V: View,
+    P: Param + 'static,
+    FMap: Fn(&Arc<GranularDelayParams>) -> &P + Copy + Send + Sync + 'static,
+{
+    if !learnable {
+        return handle;
+    }
+
+    let learning = Data::params
+        .map(move |params| params.midi_learn.learning() == Some(params_to_param(params).as_ptr()));
+    handle
+        .toggle_class("learning", learning)
+        .on_mouse_down(move |cx, button| {
+            if button == MouseButton::Right {
+                let params = Data::params.get(cx);
+                cx.emit(EditorEvent::Learn(params_to_param(&params).as_ptr()));
+            }
+        })
+}
+
+fn param_slider<P, FMap>(cx: &mut Context, label: &'static str, params_to_param: FMap)
+where
+    P: Param + 'static,
+    FMap: Fn(&Arc<GranularDelayParams>) -> &P + Copy + Send + Sync + 'static,
+{
+    Label::new(cx, label);
+    learn_slider(cx, params_to_param)
+        .bottom(Pixels(10.0))
+        .set_style(ParamSliderStyle::FromLeft);
+}
+
+// sync toggle next to the note division it switches to
+fn sync_row<S, D, SMap, DMap>(cx: &mut Context, sync: SMap, division: DMap)
+where
+    S: Param + 'static,
+    D: Param + 'static,
+    SMap: Fn(&Arc<GranularDelayParams>) -> &S + Copy + Send + Sync + 'static,
+    DMap: Fn(&Arc<GranularDelayParams>) -> &D + Copy + Send + Sync + 'static,
+{
+    HStack::new(cx, |cx| {
+        learn_button(cx, sync).width(Pixels(60.0));
+        learn_slider(cx, division)
+            .width(Pixels(115.0))
+            .set_style(ParamSliderStyle::FromLeft);
+    })
+    .col_between(Pixels(5.0))
+    .height(Auto)
+    .bottom(Pixels(10.0));
+}
@@ -0,0 +1,50 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use nih_plug_vizia::vizia::{
+    context::{Context, DrawContext},
+    vg::{Color, Paint, Path},
+    view::{Canvas, Handle, View},
+};
+
+use crate::delay::DrawData;
+
+// current output of one lfo after depth, drawn from the center
+pub struct LfoMeter {
+    draw_data: Arc<DrawData>,
+    index: usize,
+}
+
+impl LfoMeter {
+    pub fn new(cx: &mut Context, draw_data: Arc<DrawData>, index: usize) -> Handle<Self> {
+        Self { draw_data, index }.build(cx, |_cx| ())
+    }
+}
+
+impl View for LfoMeter {
+    fn element(&self) -> Option<&'static str> {
+        Some("lfo-meter")
+    }
+    fn draw(&self, cx: &mut DrawContext, canvas: &mut Canvas) {
+        let bounds = cx.bounds();
+        if bounds.w == 0.0 || bounds.h == 0.0 {
+            return;
+        }
+
+        let value = self.draw_data.lfos[self.index]
+            .load(Ordering::Relaxed)
+            .clamp(-1.0, 1.0);
+        let center = bounds.x + bounds.w / 2.0;
+        let width = bounds.w / 2.0 * value;
+
+        let paint = Paint::color(Color::rgb(70, 70, 70));
+        let mut path = Path::new();
+        path.rect(center.min(center + width), bounds.y, width.abs(), bounds.h);
+        canvas.fill_path(&path, &paint);
+
+        let paint = Paint::color(Color::rgb(230, 230, 230));
+        let mut path = Path::new();
+        path.rect(center - 1.0, bounds.y, 2.0, bounds.h);
+        canvas.fill_path(&path, &paint);
+    }
+}
@@ -1,49 +1,22 @@
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 
-use nih_plug::params::Param;
-use nih_plug_vizia::{
-    vizia::{
-        binding::Lens,
-        context::{Context, DrawContext},
-        vg::{Color, Paint, Path, Solidity},
-        view::{Canvas, Handle, View},
-    },
-    widgets::param_base::ParamWidgetBase,
+use nih_plug_vizia::vizia::{
+    context::{Context, DrawContext},
+    vg::{Color, Paint, Path, Solidity},
+    view::{Canvas, Handle, View},
 };
-use triple_buffer::Output;
 
+use super::COLORS;
 use crate::delay::DrawData;
 
-const RED: (u8, u8, u8) = (201, 104, 104);
-const GREEN: (u8, u8, u8) = (165, 182, 141);
-
 pub struct Waveform {
-    dist_a_param: ParamWidgetBase,
-    dist_b_param: ParamWidgetBase,
-    draw_data: Arc<Mutex<Output<DrawData>>>,
+    draw_data: Arc<DrawData>,
 }
 
 impl Waveform {
-    pub fn new<L, Params, P, AMap, BMap>(
-        cx: &mut Context,
-        draw_data: Arc<Mutex<Output<DrawData>>>,
-        params: L,
-        params_to_param_dist_a: AMap,
-        params_to_param_dist_b: BMap,
-    ) -> Handle<Self>
-    where
-        L: Lens<Target = Params> + Clone,
-        Params: 'static,
-        P: Param + 'static,
-        AMap: Fn(&Params) -> &P + Copy + 'static,
-        BMap: Fn(&Params) -> &P + Copy + 'static,
-    {
-        Self {
-            dist_a_param: ParamWidgetBase::new(cx, params, params_to_param_dist_a),
-            dist_b_param: ParamWidgetBase::new(cx, params, params_to_param_dist_b),
-            draw_data,
-        }
-        .build(cx, |_cx| ())
+    pub fn new(cx: &mut Context, draw_data: Arc<DrawData>) -> Handle<Self> {
+        Self { draw_data }.build(cx, |_cx| ())
     }
 }
 
@@ -56,18 +29,28 @@ impl View for Waveform {
         if bounds.w == 0.0 || bounds.h == 0.0 {
             return;
         }
-        let mut data = self.draw_data.lock().unwrap();
-        let draw_data = data.read();
-        let buffer = draw_data.buffer.clone();
-        let grains = draw_data.grains.clone();
+        // the audio thread may update the data while it is drawn, a torn frame is not noticeable
+        let draw_data = &self.draw_data;
+
+        // Freeze
+        if draw_data.frozen.load(Ordering::Relaxed) {
+            let paint = Paint::color(Color::rgba(125, 157, 201, 60));
+            let mut path = Path::new();
+            path.rect(bounds.x, bounds.y, bounds.w, bounds.h);
+            canvas.fill_path(&path, &paint);
+        }
 
         // Waveform
         let paint = Paint::color(Color::rgb(200, 200, 200));
         let mut path = Path::new();
 
-        for (i, sample) in buffer.iter().enumerate() {
+        // oldest bar first
+        let bar_num = draw_data.bars.len();
+        let bar_index = draw_data.bar_index.load(Ordering::Relaxed);
+        for i in 0..bar_num {
+            let sample = draw_data.bars[(bar_index + i) % bar_num].load(Ordering::Relaxed);
             path.rect(
-                bounds.x + bounds.w * i as f32 / buffer.len() as f32,
+                bounds.x + bounds.w * i as f32 / bar_num as f32,
                 (bounds.y + bounds.h / 2.0) - (bounds.h * sample / 2.0),
                 2.0,
                 bounds.h * sample,
@@ -75,39 +58,35 @@ impl View for Waveform {
         }
         canvas.fill_path(&path, &paint);
 
-        // Playhead A
-        let paint = Paint::color(Color::rgb(RED.0, RED.1, RED.2));
-        let mut path = Path::new();
-
-        path.rect(
-            bounds.x + bounds.w * (1.0 - self.dist_a_param.unmodulated_normalized_value()) - 2.5,
-            bounds.y,
-            5.0,
-            bounds.h,
-        );
-        canvas.fill_path(&path, &paint);
+        // Playheads
+        for (play_head, (r, g, b)) in draw_data.play_heads.iter().zip(COLORS) {
+            if !play_head.enabled.load(Ordering::Relaxed) {
+                continue;
+            }
+            let distance = play_head.distance.load(Ordering::Relaxed);
 
-        // Playhead B
-        let paint = Paint::color(Color::rgb(GREEN.0, GREEN.1, GREEN.2));
-        let mut path = Path::new();
+            let paint = Paint::color(Color::rgb(r, g, b));
+            let mut path = Path::new();
 
-        path.rect(
-            bounds.x + bounds.w * (1.0 - self.dist_b_param.unmodulated_normalized_value()) - 2.5,
-            bounds.y,
-            5.0,
-            bounds.h,
-        );
-        canvas.fill_path(&path, &paint);
+            path.rect(
+                bounds.x + bounds.w * (1.0 - distance) - 2.5,
+                bounds.y,
+                5.0,
+                bounds.h,
+            );
+            canvas.fill_path(&path, &paint);
+        }
 
         // Grains
         let paint = Paint::color(Color::hex("#F6EABE"));
-        grains.iter().for_each(|data| {
+        let grain_count = draw_data.grain_count.load(Ordering::Relaxed);
+        draw_data.grains.iter().take(grain_count).for_each(|data| {
             let mut path = Path::new();
-            let y = (data.stereo_pos + 1.0) / 2.0;
+            let y = (data.stereo_pos.load(Ordering::Relaxed) + 1.0) / 2.0;
             path.arc(
-                bounds.x + bounds.w * data.pos,
+                bounds.x + bounds.w * data.pos.load(Ordering::Relaxed),
                 bounds.y + bounds.h * y,
-                1.0 + 5.0 * data.gain,
+                1.0 + 5.0 * data.gain.load(Ordering::Relaxed),
                 0.0,
                 2.0 * std::f32::consts::PI,
                 Solidity::Hole,
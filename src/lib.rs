@@ -1,39 +1,123 @@
 mod delay;
-use delay::DrawData;
+use delay::{
+    BufferData, DrawData, FeedbackSrc, FilterMode, Harmony, LfoShape, LfoTarget, MidiMode,
+    NoteDivision, PanLaw, Quality, SaturationMode, Scale, Source, Stealing, StereoMode,
+    TriggerMode, WindowShape, GRAIN_NUM, LFO_NUM, PLAY_HEADS,
+};
+use midi_learn::{CcValues, MidiLearn};
 use nih_plug::prelude::*;
 use nih_plug_vizia::ViziaState;
-use std::sync::{Arc, Mutex};
-use triple_buffer::{triple_buffer, Output};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Arc;
 
 mod editor;
+mod midi_learn;
 
 pub struct GranularDelay {
     params: Arc<GranularDelayParams>,
     delay: delay::Delay,
-    buf_output: Arc<Mutex<Output<DrawData>>>,
+    draw_data: Arc<DrawData>,
+    buffer_sender: SyncSender<(BufferData, f32, u32)>, // data, length and generation
+    buffer_receiver: Receiver<(BufferData, f32, u32)>,
+    // a new buffer is allocated or waiting to be swapped in, the task executor clears it when the
+    // buffer could not be handed over so the audio thread asks again
+    buffer_requested: Arc<AtomicBool>,
+    // counts the initializations, buffers allocated for an earlier sample rate are dropped
+    buffer_generation: Arc<AtomicU32>,
+    cc_values: CcValues, // replace the values of parameters bound to a moved controller
+}
+
+pub enum Task {
+    AllocateBuffer {
+        length_sec: f32,
+        sample_rate: f32,
+        generation: u32,
+    },
+    DropBuffer(BufferData),
+}
+
+const SMOOTHING_MS: f32 = 20.0;
+const DEFAULT_DISTANCES: [f32; PLAY_HEADS] = [0.25, 0.5, 0.75, 0.125, 0.375, 0.625, 0.875, 0.95];
+
+// playhead indices are shown as the letter of the playhead
+fn playhead_to_string(value: i32) -> String {
+    char::from(b'A' + value as u8).to_string()
+}
+
+fn string_to_playhead(string: &str) -> Option<i32> {
+    let letter = string.trim().chars().next()?.to_ascii_uppercase();
+    let index = letter as i32 - 'A' as i32;
+    (0..PLAY_HEADS as i32).contains(&index).then_some(index)
 }
 
 #[derive(Params)]
 struct PlayheadParams {
+    #[id = "enabled"]
+    pub enabled: BoolParam,
     #[id = "dens"]
     pub density: FloatParam,
+    #[id = "densSync"]
+    pub density_sync: BoolParam,
+    #[id = "densDivision"]
+    pub density_division: EnumParam<NoteDivision>,
     #[id = "distance"]
     pub distance: FloatParam,
+    #[id = "distanceSync"]
+    pub distance_sync: BoolParam,
+    #[id = "distanceDivision"]
+    pub distance_division: EnumParam<NoteDivision>,
     #[id = "windowSize"]
     pub window_size: FloatParam,
+    #[id = "windowShape"]
+    pub window_shape: EnumParam<WindowShape>,
+    #[id = "windowFlat"]
+    pub window_flat: FloatParam,
     #[id = "grainSize"]
     pub grain_size: FloatParam,
     #[id = "pitch"]
     pub pitch: IntParam,
+    #[id = "fine"]
+    pub fine: FloatParam,
+    #[id = "pitchSpread"]
+    pub pitch_spread: FloatParam,
+    #[id = "harmony"]
+    pub harmony: EnumParam<Scale>,
+    #[id = "harmonyIntervals"]
+    pub harmony_intervals: IntParam,
+    #[id = "harmonyOctaves"]
+    pub harmony_octaves: IntParam,
+    #[id = "harmonyAmount"]
+    pub harmony_amount: FloatParam,
+    #[id = "harmonyWeighting"]
+    pub harmony_weighting: FloatParam,
     #[id = "gain"]
     pub gain: FloatParam,
+    #[id = "triggerMode"]
+    pub trigger_mode: EnumParam<TriggerMode>,
     #[id = "chaos"]
     pub chaos: FloatParam,
+    #[id = "grainLimit"]
+    pub grain_limit: IntParam,
+    #[id = "stealing"]
+    pub stealing: EnumParam<Stealing>,
+    #[id = "reverse"]
+    pub reverse: FloatParam,
+    #[id = "spread"]
+    pub spread: FloatParam,
+    #[id = "pan"]
+    pub pan: FloatParam,
+    #[id = "source"]
+    pub source: EnumParam<Source>,
+    #[id = "feedbackSrc"]
+    pub feedback_src: EnumParam<FeedbackSrc>,
 }
 
 impl PlayheadParams {
-    fn new(distance: f32) -> Self {
+    fn new(distance: f32, enabled: bool) -> Self {
         PlayheadParams {
+            enabled: BoolParam::new("Enabled", enabled),
+
             density: FloatParam::new(
                 "Density",
                 1.0,
@@ -44,6 +128,10 @@ impl PlayheadParams {
             )
             .with_value_to_string(formatters::v2s_f32_hz_then_khz(2)),
 
+            density_sync: BoolParam::new("Density Sync", false),
+
+            density_division: EnumParam::new("Density Division", NoteDivision::Eighth),
+
             distance: FloatParam::new(
                 "Distance",
                 distance,
@@ -52,6 +140,10 @@ impl PlayheadParams {
             .with_unit(" %")
             .with_value_to_string(formatters::v2s_f32_percentage(0)),
 
+            distance_sync: BoolParam::new("Distance Sync", false),
+
+            distance_division: EnumParam::new("Distance Division", NoteDivision::QuarterDotted),
+
             window_size: FloatParam::new(
                 "Window Size",
                 0.25,
@@ -60,6 +152,16 @@ impl PlayheadParams {
             .with_unit(" %")
             .with_value_to_string(formatters::v2s_f32_percentage(0)),
 
+            window_shape: EnumParam::new("Window Shape", WindowShape::Sine),
+
+            window_flat: FloatParam::new(
+                "Flat Top",
+                0.5,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(0)),
+
             grain_size: FloatParam::new(
                 "Grain Size",
                 0.5,
@@ -70,14 +172,178 @@ impl PlayheadParams {
 
             gain: FloatParam::new("Gain", 1.0, FloatRange::Linear { min: 0.0, max: 1.0 })
                 .with_unit(" %")
-                .with_value_to_string(formatters::v2s_f32_percentage(0)),
+                .with_value_to_string(formatters::v2s_f32_percentage(0))
+                .with_smoother(SmoothingStyle::Linear(SMOOTHING_MS)),
+
+            trigger_mode: EnumParam::new("Trigger Mode", TriggerMode::Jitter),
 
             chaos: FloatParam::new("Chaos", 1.0, FloatRange::Linear { min: 0.0, max: 1.0 })
                 .with_unit(" %")
                 .with_value_to_string(formatters::v2s_f32_percentage(0)),
 
-            pitch: IntParam::new("Pitch", 0, IntRange::Linear { min: -12, max: 12 })
+            grain_limit: IntParam::new(
+                "Grain Limit",
+                GRAIN_NUM as i32,
+                IntRange::Linear {
+                    min: 1,
+                    max: GRAIN_NUM as i32,
+                },
+            ),
+
+            stealing: EnumParam::new("Voice Stealing", Stealing::DropNew),
+
+            reverse: FloatParam::new("Reverse", 0.0, FloatRange::Linear { min: 0.0, max: 1.0 })
+                .with_unit(" %")
+                .with_value_to_string(formatters::v2s_f32_percentage(0)),
+
+            spread: FloatParam::new("Spread", 1.0, FloatRange::Linear { min: 0.0, max: 1.0 })
+                .with_unit(" %")
+                .with_value_to_string(formatters::v2s_f32_percentage(0))
+                .with_smoother(SmoothingStyle::Linear(SMOOTHING_MS)),
+
+            pan: FloatParam::new(
+                "Pan Center",
+                0.0,
+                FloatRange::Linear {
+                    min: -1.0,
+                    max: 1.0,
+                },
+            )
+            .with_value_to_string(formatters::v2s_f32_panning())
+            .with_string_to_value(formatters::s2v_f32_panning())
+            .with_smoother(SmoothingStyle::Linear(SMOOTHING_MS)),
+
+            source: EnumParam::new("Source", Source::Main),
+
+            feedback_src: EnumParam::new("Feedback Source", FeedbackSrc::Playhead),
+
+            pitch: IntParam::new("Pitch", 0, IntRange::Linear { min: -24, max: 24 })
                 .with_unit(" st"),
+
+            fine: FloatParam::new(
+                "Fine",
+                0.0,
+                FloatRange::Linear {
+                    min: -100.0,
+                    max: 100.0,
+                },
+            )
+            .with_unit(" ct")
+            .with_value_to_string(formatters::v2s_f32_rounded(0)),
+
+            pitch_spread: FloatParam::new(
+                "Pitch Spread",
+                0.0,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 12.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_unit(" st")
+            .with_value_to_string(formatters::v2s_f32_rounded(2)),
+
+            harmony: EnumParam::new("Harmony", Scale::Off),
+
+            // bitmask of the intervals used by the custom scale, root and fifth by default
+            harmony_intervals: IntParam::new(
+                "Custom Intervals",
+                0b0000_1000_0001,
+                IntRange::Linear { min: 0, max: 4095 },
+            )
+            .with_value_to_string(Arc::new(delay::mask_to_string))
+            .with_string_to_value(Arc::new(delay::string_to_mask)),
+
+            harmony_octaves: IntParam::new(
+                "Harmony Octaves",
+                1,
+                IntRange::Linear { min: 0, max: 3 },
+            )
+            .with_unit(" oct"),
+
+            harmony_amount: FloatParam::new(
+                "Harmony Amount",
+                1.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(0)),
+
+            // 0 picks every interval equally often, higher values favor the consonant ones
+            harmony_weighting: FloatParam::new(
+                "Harmony Weighting",
+                1.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(0)),
+        }
+    }
+}
+
+#[derive(Params)]
+struct LfoParams {
+    #[id = "shape"]
+    pub shape: EnumParam<LfoShape>,
+    #[id = "rate"]
+    pub rate: FloatParam,
+    #[id = "rateSync"]
+    pub rate_sync: BoolParam,
+    #[id = "rateDivision"]
+    pub rate_division: EnumParam<NoteDivision>,
+    #[id = "target"]
+    pub target: EnumParam<LfoTarget>,
+    #[id = "playhead"]
+    pub playhead: IntParam,
+    #[id = "depth"]
+    pub depth: FloatParam,
+}
+
+impl Default for LfoParams {
+    fn default() -> Self {
+        Self {
+            shape: EnumParam::new("Shape", LfoShape::Sine),
+
+            rate: FloatParam::new(
+                "Rate",
+                0.5,
+                FloatRange::Skewed {
+                    min: 0.01,
+                    max: 20.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_value_to_string(formatters::v2s_f32_hz_then_khz(2)),
+
+            rate_sync: BoolParam::new("Rate Sync", false),
+
+            rate_division: EnumParam::new("Rate Division", NoteDivision::Whole),
+
+            target: EnumParam::new("Target", LfoTarget::Off),
+
+            // feedback and color ignore it
+            playhead: IntParam::new(
+                "Playhead",
+                0,
+                IntRange::Linear {
+                    min: 0,
+                    max: PLAY_HEADS as i32 - 1,
+                },
+            )
+            .with_value_to_string(Arc::new(playhead_to_string))
+            .with_string_to_value(Arc::new(string_to_playhead)),
+
+            depth: FloatParam::new(
+                "Depth",
+                0.25,
+                FloatRange::Linear {
+                    min: -1.0,
+                    max: 1.0,
+                },
+            )
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_smoother(SmoothingStyle::Linear(SMOOTHING_MS)),
         }
     }
 }
@@ -86,29 +352,78 @@ impl PlayheadParams {
 struct GranularDelayParams {
     #[persist = "editor-state"]
     editor_state: Arc<ViziaState>,
+    #[persist = "midi-mappings"]
+    midi_learn: Arc<MidiLearn>,
 
-    #[nested(id_prefix = "a", group = "playheads")]
-    playhead_a: PlayheadParams,
-    #[nested(id_prefix = "b", group = "playheads")]
-    playhead_b: PlayheadParams,
+    #[nested(array, group = "Playhead")]
+    playheads: [PlayheadParams; PLAY_HEADS],
+
+    #[nested(array, group = "LFO")]
+    lfos: [LfoParams; LFO_NUM],
 
     #[id = "feedback"]
     pub feedback: FloatParam,
     #[id = "color"]
     pub color: FloatParam,
+    #[id = "filterMode"]
+    pub filter_mode: EnumParam<FilterMode>,
+    #[id = "resonance"]
+    pub resonance: FloatParam,
+    #[id = "saturation"]
+    pub saturation: EnumParam<SaturationMode>,
+    #[id = "drive"]
+    pub drive: FloatParam,
     #[id = "dry"]
     pub dry: FloatParam,
     #[id = "wet"]
     pub wet: FloatParam,
+    #[id = "panLaw"]
+    pub pan_law: EnumParam<PanLaw>,
+    #[id = "stereoMode"]
+    pub stereo_mode: EnumParam<StereoMode>,
+    #[id = "freeze"]
+    pub freeze: BoolParam,
+    #[id = "bufferLength"]
+    pub buffer_length: FloatParam,
+    #[id = "quality"]
+    pub quality: EnumParam<Quality>,
+    #[id = "seed"]
+    pub seed: IntParam,
+    #[id = "lockRandomness"]
+    pub lock_randomness: BoolParam,
+    #[id = "midiMode"]
+    pub midi_mode: EnumParam<MidiMode>,
+    #[id = "midiPlayhead"]
+    pub midi_playhead: IntParam,
+    #[id = "rootNote"]
+    pub root_note: IntParam,
+    #[id = "burstSize"]
+    pub burst_size: IntParam,
 }
 
 impl Default for GranularDelay {
     fn default() -> Self {
-        let (buf_input, buf_output) = triple_buffer(&DrawData::new());
+        let draw_data = Arc::new(DrawData::new());
+        let (buffer_sender, buffer_receiver) = sync_channel(1);
+        let params = Arc::new(GranularDelayParams::default());
+        // the buffer length would reallocate on every controller message
+        let learnable = params
+            .param_map()
+            .into_iter()
+            .filter(|(id, ..)| id != "bufferLength")
+            .collect();
+        // SAFETY: the parameters are fields of the same params object as the MidiLearn, which is
+        // only reachable through it
+        unsafe { params.midi_learn.set_params(learnable) };
         Self {
-            params: Arc::new(GranularDelayParams::default()),
-            delay: delay::Delay::new(buf_input),
-            buf_output: Arc::new(Mutex::new(buf_output)),
+            params,
+            delay: delay::Delay::new(draw_data.clone()),
+            draw_data,
+            buffer_sender,
+            buffer_receiver,
+            buffer_requested: Arc::new(AtomicBool::new(false)),
+            buffer_generation: Arc::new(AtomicU32::new(0)),
+            cc_values: CcValues::new(),
         }
     }
 }
@@ -117,28 +432,242 @@ impl Default for GranularDelayParams {
     fn default() -> Self {
         Self {
             editor_state: editor::default_state(),
+            midi_learn: Arc::new(MidiLearn::new()),
+
+            playheads: std::array::from_fn(|index| {
+                PlayheadParams::new(DEFAULT_DISTANCES[index], index < 2)
+            }),
 
-            playhead_a: PlayheadParams::new(0.25),
-            playhead_b: PlayheadParams::new(0.5),
+            lfos: Default::default(),
 
             feedback: FloatParam::new("Feedback", 0.45, FloatRange::Linear { min: 0.0, max: 1.0 })
                 .with_unit(" %")
-                .with_value_to_string(formatters::v2s_f32_percentage(0)),
+                .with_value_to_string(formatters::v2s_f32_percentage(0))
+                .with_smoother(SmoothingStyle::Linear(SMOOTHING_MS)),
 
             color: FloatParam::new("Color", 0.5, FloatRange::Linear { min: 0.0, max: 1.0 })
-                .with_value_to_string(formatters::v2s_f32_rounded(2)),
+                .with_value_to_string(formatters::v2s_f32_rounded(2))
+                .with_smoother(SmoothingStyle::Linear(SMOOTHING_MS)),
+
+            filter_mode: EnumParam::new("Filter Mode", FilterMode::Lowpass),
+
+            resonance: FloatParam::new(
+                "Resonance",
+                0.707,
+                FloatRange::Skewed {
+                    min: 0.5,
+                    max: 10.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_value_to_string(formatters::v2s_f32_rounded(2))
+            .with_smoother(SmoothingStyle::Linear(SMOOTHING_MS)),
+
+            saturation: EnumParam::new("Saturation", SaturationMode::Off),
+
+            drive: FloatParam::new(
+                "Drive",
+                0.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 24.0,
+                },
+            )
+            .with_unit(" dB")
+            .with_value_to_string(formatters::v2s_f32_rounded(1))
+            .with_smoother(SmoothingStyle::Linear(SMOOTHING_MS)),
 
             dry: FloatParam::new("Dry", 1.0, FloatRange::Linear { min: 0.0, max: 1.0 })
                 .with_unit(" %")
-                .with_value_to_string(formatters::v2s_f32_percentage(0)),
+                .with_value_to_string(formatters::v2s_f32_percentage(0))
+                .with_smoother(SmoothingStyle::Linear(SMOOTHING_MS)),
 
             wet: FloatParam::new("Wet", 0.85, FloatRange::Linear { min: 0.0, max: 1.0 })
                 .with_unit(" %")
-                .with_value_to_string(formatters::v2s_f32_percentage(0)),
+                .with_value_to_string(formatters::v2s_f32_percentage(0))
+                .with_smoother(SmoothingStyle::Linear(SMOOTHING_MS)),
+
+            pan_law: EnumParam::new("Pan Law", PanLaw::Linear),
+
+            stereo_mode: EnumParam::new("Stereo Mode", StereoMode::LeftRight),
+
+            freeze: BoolParam::new("Freeze", false),
+
+            buffer_length: FloatParam::new(
+                "Buffer Length",
+                5.0,
+                FloatRange::Skewed {
+                    min: 0.5,
+                    max: 30.0,
+                    factor: FloatRange::skew_factor(-1.0),
+                },
+            )
+            .with_unit(" s")
+            .with_value_to_string(formatters::v2s_f32_rounded(2))
+            // every change allocates a new buffer
+            .non_automatable(),
+
+            quality: EnumParam::new("Quality", Quality::Normal),
+
+            seed: IntParam::new("Seed", 0, IntRange::Linear { min: 0, max: 9999 }),
+
+            lock_randomness: BoolParam::new("Lock Randomness", false),
+
+            midi_mode: EnumParam::new("MIDI Mode", MidiMode::Off),
+
+            midi_playhead: IntParam::new(
+                "MIDI Playhead",
+                0,
+                IntRange::Linear {
+                    min: 0,
+                    max: PLAY_HEADS as i32 - 1,
+                },
+            )
+            .with_value_to_string(Arc::new(playhead_to_string))
+            .with_string_to_value(Arc::new(string_to_playhead)),
+
+            root_note: IntParam::new("Root Note", 60, IntRange::Linear { min: 0, max: 127 })
+                .with_value_to_string(formatters::v2s_i32_note_formatter())
+                .with_string_to_value(formatters::s2v_i32_note_formatter()),
+
+            burst_size: IntParam::new("Burst Size", 4, IntRange::Linear { min: 0, max: 16 })
+                .with_unit(" grains"),
         }
     }
 }
 
+impl GranularDelay {
+    // settings that are either discrete or tied to the host grid, they only change at the
+    // automation points the buffer is split at
+    fn apply_block_params(&mut self) {
+        for (index, playhead) in self.params.playheads.iter().enumerate() {
+            self.delay
+                .set_enabled(index, self.cc_values.value(&playhead.enabled));
+            // the trigger mode decides whether synced density is jittered around the grid
+            self.delay
+                .set_trigger_mode(index, self.cc_values.value(&playhead.trigger_mode));
+            if self.cc_values.value(&playhead.distance_sync) {
+                self.delay
+                    .set_distance_synced(index, self.cc_values.value(&playhead.distance_division));
+            }
+            if self.cc_values.value(&playhead.density_sync) {
+                self.delay
+                    .set_density_synced(index, self.cc_values.value(&playhead.density_division));
+            }
+            self.delay
+                .set_window_shape(index, self.cc_values.value(&playhead.window_shape));
+            self.delay
+                .set_source(index, self.cc_values.value(&playhead.source));
+            self.delay
+                .set_feedback_src(index, self.cc_values.value(&playhead.feedback_src));
+            self.delay
+                .set_grain_num(index, self.cc_values.value(&playhead.grain_limit) as usize);
+            self.delay
+                .set_stealing(index, self.cc_values.value(&playhead.stealing));
+        }
+
+        for (index, lfo) in self.params.lfos.iter().enumerate() {
+            self.delay
+                .set_lfo_shape(index, self.cc_values.value(&lfo.shape));
+            self.delay.set_lfo_target(
+                index,
+                self.cc_values.value(&lfo.target),
+                self.cc_values.value(&lfo.playhead) as usize,
+            );
+            if self.cc_values.value(&lfo.rate_sync) {
+                self.delay
+                    .set_lfo_rate_synced(index, self.cc_values.value(&lfo.rate_division));
+            }
+        }
+
+        self.delay
+            .set_pan_law(self.cc_values.value(&self.params.pan_law));
+        self.delay
+            .set_stereo_mode(self.cc_values.value(&self.params.stereo_mode));
+        self.delay
+            .set_freeze(self.cc_values.value(&self.params.freeze));
+        self.delay
+            .set_quality(self.cc_values.value(&self.params.quality));
+        self.delay.set_midi(
+            self.cc_values.value(&self.params.midi_mode),
+            self.cc_values.value(&self.params.midi_playhead) as usize,
+            self.cc_values.value(&self.params.root_note) as u8,
+            self.cc_values.value(&self.params.burst_size) as usize,
+        );
+    }
+
+    // continuous settings, their smoothers advance by one step on every call
+    fn apply_sample_params(&mut self) {
+        for (index, playhead) in self.params.playheads.iter().enumerate() {
+            if !self.cc_values.value(&playhead.distance_sync) {
+                self.delay
+                    .set_distance(index, self.cc_values.smoothed(&playhead.distance));
+            }
+            if !self.cc_values.value(&playhead.density_sync) {
+                self.delay
+                    .set_density(index, self.cc_values.smoothed(&playhead.density));
+            }
+            self.delay
+                .set_window_size(index, self.cc_values.smoothed(&playhead.window_size));
+            self.delay
+                .set_window_flat(index, self.cc_values.smoothed(&playhead.window_flat));
+            self.delay
+                .set_grain_size(index, self.cc_values.smoothed(&playhead.grain_size));
+            self.delay.set_pitch(
+                index,
+                self.cc_values.value(&playhead.pitch) as f32
+                    + self.cc_values.smoothed(&playhead.fine) / 100.0,
+            );
+            self.delay
+                .set_gain(index, self.cc_values.smoothed(&playhead.gain));
+            self.delay
+                .set_chaos(index, self.cc_values.smoothed(&playhead.chaos));
+            self.delay
+                .set_reverse(index, self.cc_values.smoothed(&playhead.reverse));
+            self.delay
+                .set_spread(index, self.cc_values.smoothed(&playhead.spread));
+            self.delay
+                .set_pan(index, self.cc_values.smoothed(&playhead.pan));
+            self.delay
+                .set_pitch_spread(index, self.cc_values.smoothed(&playhead.pitch_spread));
+            self.delay.set_harmony(
+                index,
+                Harmony {
+                    scale: self.cc_values.value(&playhead.harmony),
+                    custom: self.cc_values.value(&playhead.harmony_intervals) as u16,
+                    octaves: self.cc_values.value(&playhead.harmony_octaves) as u32,
+                    amount: self.cc_values.smoothed(&playhead.harmony_amount),
+                    weighting: self.cc_values.smoothed(&playhead.harmony_weighting),
+                },
+            );
+        }
+
+        for (index, lfo) in self.params.lfos.iter().enumerate() {
+            if !self.cc_values.value(&lfo.rate_sync) {
+                self.delay
+                    .set_lfo_rate(index, self.cc_values.smoothed(&lfo.rate));
+            }
+            self.delay
+                .set_lfo_depth(index, self.cc_values.smoothed(&lfo.depth));
+        }
+
+        self.delay
+            .set_dry(self.cc_values.smoothed(&self.params.dry));
+        self.delay
+            .set_wet(self.cc_values.smoothed(&self.params.wet));
+        self.delay.set_saturation(
+            self.cc_values.value(&self.params.saturation),
+            util::db_to_gain(self.cc_values.smoothed(&self.params.drive)),
+        );
+        self.delay.feedback = self.cc_values.smoothed(&self.params.feedback);
+        self.delay.set_filter(
+            self.cc_values.value(&self.params.filter_mode),
+            self.cc_values.smoothed(&self.params.color),
+            self.cc_values.smoothed(&self.params.resonance),
+        );
+    }
+}
+
 impl Plugin for GranularDelay {
     const NAME: &'static str = "Oh My Grain";
     const VENDOR: &'static str = "Christian Grothe";
@@ -149,20 +678,46 @@ impl Plugin for GranularDelay {
 
     // The first audio IO layout is used as the default. The other layouts may be selected either
     // explicitly or automatically by the host or the user depending on the plugin API/backend.
-    const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[AudioIOLayout {
-        main_input_channels: NonZeroU32::new(2),
-        main_output_channels: NonZeroU32::new(2),
-
-        aux_input_ports: &[],
-        aux_output_ports: &[],
-
-        // Individual ports and the layout as a whole can be named here. By default these names
-        // are generated as needed. This layout will be called 'Stereo', while a layout with
-        // only one input and output channel would be called 'Mono'.
-        names: PortNames::const_default(),
-    }];
-
-    const MIDI_INPUT: MidiConfig = MidiConfig::None;
+    // the delay runs in stereo internally, mono inputs are spread onto both sides
+    const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[
+        AudioIOLayout {
+            main_input_channels: NonZeroU32::new(2),
+            main_output_channels: NonZeroU32::new(2),
+
+            aux_input_ports: &[new_nonzero_u32(2)],
+            aux_output_ports: &[],
+
+            // Individual ports and the layout as a whole can be named here. By default these names
+            // are generated as needed. This layout will be called 'Stereo', while a layout with
+            // only one input and output channel would be called 'Mono'.
+            names: PortNames {
+                aux_inputs: &["Sidechain"],
+                ..PortNames::const_default()
+            },
+        },
+        AudioIOLayout {
+            main_input_channels: NonZeroU32::new(1),
+            main_output_channels: NonZeroU32::new(2),
+            aux_input_ports: &[new_nonzero_u32(2)],
+            names: PortNames {
+                aux_inputs: &["Sidechain"],
+                ..PortNames::const_default()
+            },
+            ..AudioIOLayout::const_default()
+        },
+        AudioIOLayout {
+            main_input_channels: NonZeroU32::new(1),
+            main_output_channels: NonZeroU32::new(1),
+            aux_input_ports: &[new_nonzero_u32(1)],
+            names: PortNames {
+                aux_inputs: &["Sidechain"],
+                ..PortNames::const_default()
+            },
+            ..AudioIOLayout::const_default()
+        },
+    ];
+
+    const MIDI_INPUT: MidiConfig = MidiConfig::MidiCCs;
     const MIDI_OUTPUT: MidiConfig = MidiConfig::None;
 
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
@@ -171,89 +726,177 @@ impl Plugin for GranularDelay {
     // messages here. The type implements the `SysExMessage` trait, which allows conversion to and
     // from plain byte buffers.
     type SysExMessage = ();
-    // More advanced plugins can use this to run expensive background tasks. See the field's
-    // documentation for more information. `()` means that the plugin does not have any background
-    // tasks.
-    type BackgroundTask = ();
+    // Buffers are allocated and dropped in the background so resizing never allocates on the
+    // audio thread.
+    type BackgroundTask = Task;
 
     fn params(&self) -> Arc<dyn Params> {
         self.params.clone()
     }
 
+    fn task_executor(&mut self) -> TaskExecutor<Self> {
+        let buffer_sender = self.buffer_sender.clone();
+        let buffer_requested = self.buffer_requested.clone();
+        let buffer_generation = self.buffer_generation.clone();
+        Box::new(move |task| match task {
+            Task::AllocateBuffer {
+                length_sec,
+                sample_rate,
+                generation,
+            } => {
+                let data = BufferData::new((length_sec * sample_rate) as usize);
+                if buffer_sender
+                    .try_send((data, length_sec, generation))
+                    .is_err()
+                {
+                    nih_error!("Failed to hand over the new buffer");
+                    // a request of an earlier initialization no longer holds the flag
+                    if generation == buffer_generation.load(Ordering::Relaxed) {
+                        buffer_requested.store(false, Ordering::Relaxed);
+                    }
+                }
+            }
+            Task::DropBuffer(data) => drop(data),
+        })
+    }
+
     fn editor(&mut self, _async_executor: AsyncExecutor<Self>) -> Option<Box<dyn Editor>> {
         editor::create(
             self.params.clone(),
             self.params.editor_state.clone(),
-            self.buf_output.clone(),
+            self.draw_data.clone(),
         )
     }
 
     fn initialize(
         &mut self,
-        _audio_io_layout: &AudioIOLayout,
+        audio_io_layout: &AudioIOLayout,
         buffer_config: &BufferConfig,
         _context: &mut impl InitContext<Self>,
     ) -> bool {
-        self.delay.init(buffer_config.sample_rate);
+        // a buffer allocated for the previous configuration is of no use anymore, one that is still
+        // being allocated is recognized by its generation once it arrives
+        self.buffer_generation.fetch_add(1, Ordering::Relaxed);
+        while self.buffer_receiver.try_recv().is_ok() {}
+        self.buffer_requested.store(false, Ordering::Relaxed);
+
+        self.delay.set_input_channels(
+            audio_io_layout
+                .main_input_channels
+                .map_or(0, |channels| channels.get() as usize),
+        );
+        self.delay
+            .init(buffer_config.sample_rate, self.params.buffer_length.value());
+        self.cc_values
+            .set_sample_rate(buffer_config.sample_rate, SMOOTHING_MS);
         true
     }
 
     fn process(
         &mut self,
         buffer: &mut Buffer,
-        _aux: &mut AuxiliaryBuffers,
-        _context: &mut impl ProcessContext<Self>,
+        aux: &mut AuxiliaryBuffers,
+        context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
-        self.delay
-            .set_distance(0, self.params.playhead_a.distance.smoothed.next());
-        self.delay
-            .set_density(0, self.params.playhead_a.density.smoothed.next());
-        self.delay
-            .set_window_size(0, self.params.playhead_a.window_size.smoothed.next());
-        self.delay
-            .set_grain_size(0, self.params.playhead_a.grain_size.smoothed.next());
-        self.delay
-            .set_pitch(0, self.params.playhead_a.pitch.smoothed.next());
-        self.delay
-            .set_gain(0, self.params.playhead_a.gain.smoothed.next());
-        self.delay
-            .set_pitch(0, self.params.playhead_a.pitch.smoothed.next());
-        self.delay
-            .set_gain(0, self.params.playhead_a.gain.smoothed.next());
-        self.delay
-            .set_chaos(0, self.params.playhead_a.chaos.smoothed.next());
+        self.cc_values.update(&self.params.midi_learn);
 
+        let transport = context.transport();
+        let beat_pos = transport.pos_beats().filter(|_| transport.playing);
         self.delay
-            .set_distance(1, self.params.playhead_b.distance.smoothed.next());
-        self.delay
-            .set_density(1, self.params.playhead_b.density.smoothed.next());
-        self.delay
-            .set_window_size(1, self.params.playhead_b.window_size.smoothed.next());
-        self.delay
-            .set_grain_size(1, self.params.playhead_b.grain_size.smoothed.next());
+            .set_seed(self.cc_values.value(&self.params.seed) as u64);
         self.delay
-            .set_pitch(1, self.params.playhead_b.pitch.smoothed.next());
-        self.delay
-            .set_gain(1, self.params.playhead_b.gain.smoothed.next());
-        self.delay
-            .set_pitch(1, self.params.playhead_b.pitch.smoothed.next());
-        self.delay
-            .set_gain(1, self.params.playhead_b.gain.smoothed.next());
-        self.delay
-            .set_chaos(1, self.params.playhead_b.chaos.smoothed.next());
-
-        self.delay.set_dry(self.params.dry.smoothed.next());
-        self.delay.set_wet(self.params.wet.smoothed.next());
-        self.delay.feedback = self.params.feedback.smoothed.next();
-        self.delay.set_cutoff(self.params.color.smoothed.next());
-
-        for channels in buffer.iter_samples() {
-            let mut sample_channels = channels.into_iter();
-            let stereo_slice = (
-                sample_channels.next().unwrap(),
-                sample_channels.next().unwrap(),
-            );
-            self.delay.render(stereo_slice);
+            .set_lock_randomness(self.cc_values.value(&self.params.lock_randomness));
+        self.delay.set_transport(
+            transport.tempo.unwrap_or(120.0) as f32,
+            transport.playing,
+            beat_pos,
+        );
+
+        self.apply_block_params();
+
+        // only one buffer is in flight at a time, later changes are picked up once it is swapped in
+        let buffer_length = self.params.buffer_length.value();
+        let generation = self.buffer_generation.load(Ordering::Relaxed);
+        if !self.buffer_requested.load(Ordering::Relaxed)
+            && buffer_length != self.delay.buffer.length_sec
+        {
+            self.buffer_requested.store(true, Ordering::Relaxed);
+            context.execute_background(Task::AllocateBuffer {
+                length_sec: buffer_length,
+                sample_rate: self.delay.sample_rate,
+                generation,
+            });
+        }
+        if let Ok((data, length_sec, buffer_generation)) = self.buffer_receiver.try_recv() {
+            if buffer_generation == generation {
+                self.delay.set_buffer(data, length_sec);
+            } else {
+                context.execute_background(Task::DropBuffer(data));
+            }
+        }
+
+        let mut sidechain_samples = aux
+            .inputs
+            .first_mut()
+            .map(|sidechain| sidechain.iter_samples());
+        let mut next_event = context.next_event();
+        for (sample_id, mut channels) in buffer.iter_samples().enumerate() {
+            self.cc_values.advance();
+            self.apply_sample_params();
+
+            while let Some(event) = next_event {
+                if event.timing() > sample_id as u32 {
+                    break;
+                }
+                match event {
+                    NoteEvent::NoteOn {
+                        channel,
+                        note,
+                        velocity,
+                        ..
+                    } => self.delay.note_on(channel, note, velocity),
+                    NoteEvent::NoteOff { channel, note, .. } => self.delay.note_off(channel, note),
+                    NoteEvent::MidiCC { cc, value, .. } => {
+                        self.params.midi_learn.process_cc(cc, value);
+                        self.cc_values.update(&self.params.midi_learn);
+                        self.apply_block_params();
+                    }
+                    _ => (),
+                }
+                next_event = context.next_event();
+            }
+
+            let mut frame = [0.0; 2];
+            let channel_num = channels.len().min(frame.len());
+            frame
+                .iter_mut()
+                .zip(channels.iter_mut())
+                .for_each(|(value, sample)| *value = *sample);
+
+            // the host may leave the sidechain unconnected
+            let mut sidechain = [0.0; 2];
+            let sidechain_num = match sidechain_samples.as_mut().and_then(Iterator::next) {
+                Some(mut sidechain_channels) => {
+                    sidechain
+                        .iter_mut()
+                        .zip(sidechain_channels.iter_mut())
+                        .for_each(|(value, sample)| *value = *sample);
+                    sidechain_channels.len().min(sidechain.len())
+                }
+                None => 0,
+            };
+
+            self.delay
+                .render(&mut frame[..channel_num], &sidechain[..sidechain_num]);
+            frame
+                .iter()
+                .zip(channels.iter_mut())
+                .for_each(|(value, sample)| *sample = *value);
+        }
+
+        if let Some(data) = self.delay.take_old_buffer() {
+            self.buffer_requested.store(false, Ordering::Relaxed);
+            context.execute_background(Task::DropBuffer(data));
         }
 
         ProcessStatus::Normal
@@ -267,7 +910,11 @@ impl ClapPlugin for GranularDelay {
     const CLAP_SUPPORT_URL: Option<&'static str> = None;
 
     // Don't forget to change these features
-    const CLAP_FEATURES: &'static [ClapFeature] = &[ClapFeature::AudioEffect, ClapFeature::Stereo];
+    const CLAP_FEATURES: &'static [ClapFeature] = &[
+        ClapFeature::AudioEffect,
+        ClapFeature::Stereo,
+        ClapFeature::Mono,
+    ];
 }
 
 impl Vst3Plugin for GranularDelay {
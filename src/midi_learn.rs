@@ -0,0 +1,530 @@
+use nih_plug::params::internals::ParamPtr;
+use nih_plug::params::persist::PersistentField;
+use nih_plug::prelude::{AtomicF32, FloatParam, Param};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+
+const CC_NUM: usize = 128;
+const NO_PARAM: usize = usize::MAX;
+const MAX_CURVE_EXPONENT: f32 = 4.0; // exponent at either end of the curve range
+
+// a controller bound to a parameter as it is stored in the plugin state
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CcMapping {
+    pub cc: u8,
+    pub param: String, // parameter id
+    pub min: f32,      // normalized value at the lowest controller position
+    pub max: f32,      // normalized value at the highest controller position
+    pub curve: f32,    // range -1 to 1, 0 is linear
+}
+
+// the persistent field has to be a local type
+#[derive(Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct CcMappings(pub Vec<CcMapping>);
+
+struct Binding {
+    param: AtomicUsize, // index into the learnable parameters, NO_PARAM if the controller is unused
+    min: AtomicF32,
+    max: AtomicF32,
+    curve: AtomicF32,
+    value: AtomicF32, // normalized, NaN while the parameter is not overridden
+    base: AtomicF32,  // normalized value of the parameter when the controller last moved
+}
+
+// shared by the audio thread and the editor, every field is atomic so neither side ever waits
+pub struct MidiLearn {
+    params: OnceLock<Vec<(String, ParamPtr, String)>>, // id, parameter and group
+    bindings: Vec<Binding>,                            // indexed by the controller number
+    learning: AtomicUsize, // parameter waiting for a controller, NO_PARAM if none
+    version: AtomicU32,    // counts changes of the bound controllers so the editor can rebuild
+    moved: AtomicU32,      // counts controller messages so the editor can pass them on to the host
+}
+
+impl MidiLearn {
+    pub fn new() -> Self {
+        Self {
+            params: OnceLock::new(),
+            bindings: (0..CC_NUM)
+                .map(|_| Binding {
+                    param: AtomicUsize::new(NO_PARAM),
+                    min: AtomicF32::new(0.0),
+                    max: AtomicF32::new(1.0),
+                    curve: AtomicF32::new(0.0),
+                    value: AtomicF32::new(f32::NAN),
+                    base: AtomicF32::new(0.0),
+                })
+                .collect(),
+            learning: AtomicUsize::new(NO_PARAM),
+            version: AtomicU32::new(0),
+            moved: AtomicU32::new(0),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The parameters have to stay at their address for as long as this lives, which holds for the
+    /// fields of the params object this is part of.
+    pub unsafe fn set_params(&self, params: Vec<(String, ParamPtr, String)>) {
+        if self.params.set(params).is_err() {
+            nih_plug::nih_debug_assert_failure!("The learnable parameters were set twice");
+        }
+    }
+
+    fn params(&self) -> &[(String, ParamPtr, String)] {
+        self.params.get().map_or(&[], Vec::as_slice)
+    }
+
+    pub fn learnable(&self, param: ParamPtr) -> bool {
+        self.params().iter().any(|(_, ptr, _)| *ptr == param)
+    }
+
+    // starts listening for a controller, a second call for the same parameter stops it
+    pub fn learn(&self, param: ParamPtr) {
+        if let Some(index) = self.params().iter().position(|(_, ptr, _)| *ptr == param) {
+            let learning = self.learning.load(Ordering::Relaxed);
+            let learning = if learning == index { NO_PARAM } else { index };
+            self.learning.store(learning, Ordering::Relaxed);
+        }
+    }
+
+    pub fn learning(&self) -> Option<ParamPtr> {
+        let index = self.learning.load(Ordering::Relaxed);
+        self.params().get(index).map(|(_, ptr, _)| *ptr)
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version.load(Ordering::Relaxed)
+    }
+
+    pub fn moved(&self) -> u32 {
+        self.moved.load(Ordering::Relaxed)
+    }
+
+    // value range 0-1, called from the audio thread
+    pub fn process_cc(&self, cc: u8, value: f32) {
+        let Some(binding) = self.bindings.get(cc as usize) else {
+            return;
+        };
+
+        let learning = self.learning.swap(NO_PARAM, Ordering::Relaxed);
+        if learning != NO_PARAM {
+            self.bind(cc as usize, learning);
+        }
+
+        if let Some((_, param, _)) = self.params().get(binding.param.load(Ordering::Relaxed)) {
+            let min = binding.min.load(Ordering::Relaxed);
+            let max = binding.max.load(Ordering::Relaxed);
+            let exponent = MAX_CURVE_EXPONENT.powf(binding.curve.load(Ordering::Relaxed));
+            let normalized = min + (max - min) * value.powf(exponent);
+            binding
+                .base
+                .store(normalized_value(param), Ordering::Relaxed);
+            binding.value.store(normalized, Ordering::Relaxed);
+            self.moved.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // parameters overridden by their controller with the normalized controller value and the value
+    // of the parameter at the time. a change of the parameter by the host or the editor ends the
+    // override
+    pub fn values(&self) -> impl Iterator<Item = (ParamPtr, f32, f32)> + '_ {
+        self.bindings.iter().filter_map(|binding| {
+            let (_, param, _) = self.params().get(binding.param.load(Ordering::Relaxed))?;
+            let value = binding.value.load(Ordering::Relaxed);
+            let base = binding.base.load(Ordering::Relaxed);
+            (!value.is_nan() && normalized_value(param) == base).then_some((*param, value, base))
+        })
+    }
+
+    // only called from the audio thread, so a controller message can't be lost in between
+    pub fn clear_overridden(&self) {
+        for binding in self.bindings.iter() {
+            let Some((_, param, _)) = self.params().get(binding.param.load(Ordering::Relaxed))
+            else {
+                continue;
+            };
+            if normalized_value(param) != binding.base.load(Ordering::Relaxed) {
+                binding.value.store(f32::NAN, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn bind(&self, cc: usize, index: usize) {
+        // a parameter follows one controller only
+        self.bindings
+            .iter()
+            .filter(|binding| binding.param.load(Ordering::Relaxed) == index)
+            .for_each(|binding| binding.param.store(NO_PARAM, Ordering::Relaxed));
+
+        let binding = &self.bindings[cc];
+        binding.min.store(0.0, Ordering::Relaxed);
+        binding.max.store(1.0, Ordering::Relaxed);
+        binding.curve.store(0.0, Ordering::Relaxed);
+        binding.value.store(f32::NAN, Ordering::Relaxed);
+        binding.param.store(index, Ordering::Relaxed);
+        self.version.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn unbind(&self, cc: u8) {
+        if let Some(binding) = self.bindings.get(cc as usize) {
+            binding.param.store(NO_PARAM, Ordering::Relaxed);
+            binding.value.store(f32::NAN, Ordering::Relaxed);
+            self.version.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // bound controllers with the name of their parameter, for the editor
+    pub fn bound(&self) -> Vec<(u8, String)> {
+        self.bindings
+            .iter()
+            .enumerate()
+            .filter_map(|(cc, binding)| {
+                let (_, param, group) = self.params().get(binding.param.load(Ordering::Relaxed))?;
+                // SAFETY: see normalized_value
+                let name = unsafe { param.name() };
+                let name = if group.is_empty() {
+                    name.to_string()
+                } else {
+                    format!("{name} ({group})")
+                };
+                Some((cc as u8, name))
+            })
+            .collect()
+    }
+
+    pub fn min(&self, cc: u8) -> f32 {
+        self.bindings[cc as usize].min.load(Ordering::Relaxed)
+    }
+
+    pub fn max(&self, cc: u8) -> f32 {
+        self.bindings[cc as usize].max.load(Ordering::Relaxed)
+    }
+
+    pub fn curve(&self, cc: u8) -> f32 {
+        self.bindings[cc as usize].curve.load(Ordering::Relaxed)
+    }
+
+    pub fn set_min(&self, cc: u8, value: f32) {
+        self.bindings[cc as usize]
+            .min
+            .store(value.clamp(0.0, 1.0), Ordering::Relaxed);
+    }
+
+    pub fn set_max(&self, cc: u8, value: f32) {
+        self.bindings[cc as usize]
+            .max
+            .store(value.clamp(0.0, 1.0), Ordering::Relaxed);
+    }
+
+    pub fn set_curve(&self, cc: u8, value: f32) {
+        self.bindings[cc as usize]
+            .curve
+            .store(value.clamp(-1.0, 1.0), Ordering::Relaxed);
+    }
+}
+
+fn normalized_value(param: &ParamPtr) -> f32 {
+    // SAFETY: set_params only takes parameters of the params object that owns the MidiLearn, so
+    // they live at least as long as it
+    unsafe { param.unmodulated_normalized_value() }
+}
+
+// bindings are stored by parameter id, so they survive changes to the order of the parameters
+impl<'a> PersistentField<'a, CcMappings> for Arc<MidiLearn> {
+    fn set(&self, new_value: CcMappings) {
+        self.bindings
+            .iter()
+            .for_each(|binding| binding.param.store(NO_PARAM, Ordering::Relaxed));
+
+        for mapping in new_value.0 {
+            let index = self
+                .params()
+                .iter()
+                .position(|(id, ..)| *id == mapping.param);
+            if let (Some(binding), Some(index)) = (self.bindings.get(mapping.cc as usize), index) {
+                // the state may come from an older version or have been edited by hand
+                binding
+                    .min
+                    .store(mapping.min.clamp(0.0, 1.0), Ordering::Relaxed);
+                binding
+                    .max
+                    .store(mapping.max.clamp(0.0, 1.0), Ordering::Relaxed);
+                binding
+                    .curve
+                    .store(mapping.curve.clamp(-1.0, 1.0), Ordering::Relaxed);
+                // the parameter values of the state take precedence over the controllers
+                binding.value.store(f32::NAN, Ordering::Relaxed);
+                binding.param.store(index, Ordering::Relaxed);
+            }
+        }
+        self.version.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn map<F, R>(&self, f: F) -> R
+    where
+        F: Fn(&CcMappings) -> R,
+    {
+        let mappings = self
+            .bindings
+            .iter()
+            .enumerate()
+            .filter_map(|(cc, binding)| {
+                let (id, ..) = self.params().get(binding.param.load(Ordering::Relaxed))?;
+                Some(CcMapping {
+                    cc: cc as u8,
+                    param: id.clone(),
+                    min: binding.min.load(Ordering::Relaxed),
+                    max: binding.max.load(Ordering::Relaxed),
+                    curve: binding.curve.load(Ordering::Relaxed),
+                })
+            })
+            .collect();
+        f(&CcMappings(mappings))
+    }
+}
+
+struct CcValue {
+    param: ParamPtr,
+    target: f32,  // normalized
+    current: f32, // ramps towards the target like the smoothers of the float parameters
+    bound: bool,
+}
+
+// the controller values on the audio thread, they replace the values of their parameters until the
+// controller is removed or the parameter changes
+pub struct CcValues {
+    values: Vec<CcValue>,
+    step: f32, // largest change of a ramp per sample
+    sample_rate: f32,
+}
+
+impl CcValues {
+    pub fn new() -> Self {
+        Self {
+            // a parameter follows one controller only, so this never grows past the controllers
+            values: Vec::with_capacity(CC_NUM),
+            step: 1.0,
+            sample_rate: 0.0,
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f32, smoothing_ms: f32) {
+        self.step = 1000.0 / (smoothing_ms * sample_rate);
+        self.sample_rate = sample_rate;
+    }
+
+    // picks up moved controllers and ends overrides
+    pub fn update(&mut self, midi_learn: &MidiLearn) {
+        midi_learn.clear_overridden();
+
+        self.values.iter_mut().for_each(|value| value.bound = false);
+        for (param, target, base) in midi_learn.values() {
+            match self.values.iter_mut().find(|value| value.param == param) {
+                Some(value) => {
+                    value.target = target;
+                    value.bound = true;
+                }
+                // ramps from where the parameter was
+                None => self.values.push(CcValue {
+                    param,
+                    target,
+                    current: base,
+                    bound: true,
+                }),
+            }
+        }
+
+        // the smoothers take over from the controller value instead of jumping
+        for value in self.values.iter().filter(|value| !value.bound) {
+            if let ParamPtr::FloatParam(param) = value.param {
+                // SAFETY: the pointers come from the MidiLearn, see normalized_value
+                let param = unsafe { &*param };
+                param.smoothed.reset(param.preview_plain(value.current));
+                param
+                    .smoothed
+                    .set_target(self.sample_rate, param.modulated_plain_value());
+            }
+        }
+        self.values.retain(|value| value.bound);
+    }
+
+    // advances the ramps by one sample
+    pub fn advance(&mut self) {
+        for value in self.values.iter_mut() {
+            value.current += (value.target - value.current).clamp(-self.step, self.step);
+        }
+    }
+
+    fn get(&self, param: ParamPtr) -> Option<&CcValue> {
+        self.values.iter().find(|value| value.param == param)
+    }
+
+    pub fn value<P: Param>(&self, param: &P) -> P::Plain {
+        match self.get(param.as_ptr()) {
+            Some(value) => param.preview_plain(value.target),
+            None => param.modulated_plain_value(),
+        }
+    }
+
+    // advances the smoother of the parameter by one step
+    pub fn smoothed(&self, param: &FloatParam) -> f32 {
+        let smoothed = param.smoothed.next();
+        match self.get(param.as_ptr()) {
+            Some(value) => param.preview_plain(value.current),
+            None => smoothed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nih_plug::prelude::FloatRange;
+
+    const CC: u8 = 7;
+
+    fn gain() -> FloatParam {
+        FloatParam::new("Gain", 0.5, FloatRange::Linear { min: 0.0, max: 1.0 })
+    }
+
+    // the parameter has to be declared before the MidiLearn so it outlives it
+    fn midi_learn(param: &FloatParam) -> Arc<MidiLearn> {
+        let midi_learn = Arc::new(MidiLearn::new());
+        let params = vec![(String::from("gain"), param.as_ptr(), String::new())];
+        // SAFETY: the parameter outlives the MidiLearn in every test
+        unsafe { midi_learn.set_params(params) };
+        midi_learn
+    }
+
+    fn bound(param: &FloatParam) -> Arc<MidiLearn> {
+        let midi_learn = midi_learn(param);
+        midi_learn.learn(param.as_ptr());
+        midi_learn.process_cc(CC, 0.0);
+        midi_learn
+    }
+
+    fn mapped(midi_learn: &MidiLearn, value: f32) -> f32 {
+        midi_learn.process_cc(CC, value);
+        midi_learn.values().next().unwrap().1
+    }
+
+    #[test]
+    fn learning_binds_the_next_controller() {
+        let param = gain();
+        let midi_learn = midi_learn(&param);
+        midi_learn.learn(param.as_ptr());
+        assert_eq!(midi_learn.learning(), Some(param.as_ptr()));
+
+        midi_learn.process_cc(CC, 0.25);
+        assert_eq!(midi_learn.learning(), None);
+        assert_eq!(midi_learn.bound(), vec![(CC, String::from("Gain"))]);
+        assert_eq!(midi_learn.values().next().unwrap().1, 0.25);
+    }
+
+    #[test]
+    fn learning_twice_cancels() {
+        let param = gain();
+        let midi_learn = midi_learn(&param);
+        midi_learn.learn(param.as_ptr());
+        midi_learn.learn(param.as_ptr());
+        midi_learn.process_cc(CC, 0.25);
+        assert!(midi_learn.bound().is_empty());
+        assert_eq!(midi_learn.values().count(), 0);
+    }
+
+    #[test]
+    fn range_maps_the_controller() {
+        let param = gain();
+        let midi_learn = bound(&param);
+        midi_learn.set_min(CC, 0.2);
+        midi_learn.set_max(CC, 0.6);
+        assert!((mapped(&midi_learn, 0.0) - 0.2).abs() < 1e-6);
+        assert!((mapped(&midi_learn, 0.5) - 0.4).abs() < 1e-6);
+        assert!((mapped(&midi_learn, 1.0) - 0.6).abs() < 1e-6);
+
+        // a minimum above the maximum inverts the controller
+        midi_learn.set_min(CC, 1.0);
+        midi_learn.set_max(CC, 0.0);
+        assert!((mapped(&midi_learn, 0.25) - 0.75).abs() < 1e-6);
+    }
+
+    #[test]
+    fn curve_bends_the_controller() {
+        let param = gain();
+        let midi_learn = bound(&param);
+        assert!((mapped(&midi_learn, 0.5) - 0.5).abs() < 1e-6);
+
+        midi_learn.set_curve(CC, 1.0);
+        assert!((mapped(&midi_learn, 0.5) - 0.5f32.powf(4.0)).abs() < 1e-6);
+        midi_learn.set_curve(CC, -1.0);
+        assert!((mapped(&midi_learn, 0.5) - 0.5f32.powf(0.25)).abs() < 1e-6);
+        // the ends stay in place
+        assert_eq!(mapped(&midi_learn, 0.0), 0.0);
+        assert_eq!(mapped(&midi_learn, 1.0), 1.0);
+    }
+
+    #[test]
+    fn setters_clamp() {
+        let param = gain();
+        let midi_learn = bound(&param);
+        midi_learn.set_min(CC, -1.0);
+        midi_learn.set_max(CC, 2.0);
+        midi_learn.set_curve(CC, 5.0);
+        assert_eq!(
+            (midi_learn.min(CC), midi_learn.max(CC), midi_learn.curve(CC)),
+            (0.0, 1.0, 1.0)
+        );
+    }
+
+    #[test]
+    fn unbinding_ends_the_override() {
+        let param = gain();
+        let midi_learn = bound(&param);
+        midi_learn.process_cc(CC, 0.3);
+        midi_learn.unbind(CC);
+        assert!(midi_learn.bound().is_empty());
+        assert_eq!(midi_learn.values().count(), 0);
+    }
+
+    #[test]
+    fn persist_round_trip() {
+        let param = gain();
+        let midi_learn = bound(&param);
+        midi_learn.set_min(CC, 0.1);
+        midi_learn.set_max(CC, 0.9);
+        midi_learn.set_curve(CC, -0.5);
+        midi_learn.process_cc(CC, 0.3);
+        let mappings = midi_learn.map(|mappings| mappings.0.clone());
+
+        let restored = self::midi_learn(&param);
+        restored.set(CcMappings(mappings));
+        assert_eq!(restored.bound(), midi_learn.bound());
+        assert_eq!(
+            (restored.min(CC), restored.max(CC), restored.curve(CC)),
+            (0.1, 0.9, -0.5)
+        );
+        // the parameter keeps the value of the state until the controller moves
+        assert_eq!(restored.values().count(), 0);
+    }
+
+    #[test]
+    fn loading_clamps_and_skips_unknown_parameters() {
+        let param = gain();
+        let midi_learn = self::midi_learn(&param);
+        let mapping = |cc, param: &str| CcMapping {
+            cc,
+            param: String::from(param),
+            min: -1.0,
+            max: 2.0,
+            curve: 5.0,
+        };
+        midi_learn.set(CcMappings(vec![mapping(CC, "gain"), mapping(1, "missing")]));
+        assert_eq!(midi_learn.bound(), vec![(CC, String::from("Gain"))]);
+        assert_eq!(
+            (midi_learn.min(CC), midi_learn.max(CC), midi_learn.curve(CC)),
+            (0.0, 1.0, 1.0)
+        );
+    }
+}